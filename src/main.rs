@@ -1,11 +1,48 @@
-use crate::utils::server_handlers::{
-    handle_get_calldata, handle_get_calldata_by_tag, handle_get_war_calldata, handle_weave_gm,
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
 };
-use axum::{routing::get, Router};
-mod utils;
+use std::net::SocketAddr;
+use wvm_data_retriever::utils::access::{enforce_access, run_bucket_sweep};
+use wvm_data_retriever::utils::index::run_index_sync;
+use wvm_data_retriever::utils::metrics::track_requests;
+use wvm_data_retriever::utils::server_handlers::{
+    handle_acknowledge_incident, handle_archive_rpc, handle_cache_stats, handle_docs,
+    handle_get_block, handle_get_calldata, handle_get_calldata_by_tag,
+    handle_get_calldata_by_tag_v2, handle_get_calldata_v2, handle_get_war_calldata,
+    handle_get_war_calldata_v2, handle_graphiql, handle_graphql, handle_health_live,
+    handle_health_ready, handle_incidents, handle_metrics, handle_openapi, handle_usage,
+    handle_usage_of_key, handle_weave_gm,
+};
+use wvm_data_retriever::utils::telemetry::{init_tracing, propagate_request_id};
+
+/// Serves the router with the peer address of each connection as
+/// `ConnectInfo`, which the rate limiter tells anonymous clients apart by.
+struct RetrieverService(Router);
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for RetrieverService {
+    async fn bind(mut self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(shuttle_runtime::CustomError::new)?;
+        axum::serve(
+            listener,
+            self.0.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .map_err(shuttle_runtime::CustomError::new)?;
+        Ok(())
+    }
+}
 
 #[shuttle_runtime::main]
-async fn main() -> shuttle_axum::ShuttleAxum {
+async fn main() -> Result<RetrieverService, shuttle_runtime::Error> {
+    init_tracing().expect("could not set up tracing");
+    tokio::spawn(run_index_sync());
+    tokio::spawn(run_bucket_sweep());
+
     let router = Router::new()
         .route("/", get(handle_weave_gm))
         .route("/v1/calldata/:txid", get(handle_get_calldata))
@@ -13,7 +50,31 @@ async fn main() -> shuttle_axum::ShuttleAxum {
         .route(
             "/v1/calldata/tag/:tag1/:tag2",
             get(handle_get_calldata_by_tag),
-        );
+        )
+        .route("/v1/block/:number", get(handle_get_block))
+        .route("/v2/calldata/:txid", get(handle_get_calldata_v2))
+        .route("/v2/war-calldata/:txid", get(handle_get_war_calldata_v2))
+        .route(
+            "/v2/calldata/tag/:tag1/:tag2",
+            get(handle_get_calldata_by_tag_v2),
+        )
+        .route("/graphql", get(handle_graphiql).post(handle_graphql))
+        .route("/v1/rpc", post(handle_archive_rpc))
+        .route("/v1/cache/stats", get(handle_cache_stats))
+        .route_layer(middleware::from_fn(enforce_access))
+        .route_layer(middleware::from_fn(track_requests))
+        // metrics, probes, usage exports, incidents and API docs are neither tracked nor
+        // rate limited
+        .route("/metrics", get(handle_metrics))
+        .route("/health/live", get(handle_health_live))
+        .route("/health/ready", get(handle_health_ready))
+        .route("/v1/usage", get(handle_usage))
+        .route("/v1/usage/:key_id", get(handle_usage_of_key))
+        .route("/v1/incidents", get(handle_incidents))
+        .route("/v1/incidents/:id/ack", post(handle_acknowledge_incident))
+        .route("/openapi.json", get(handle_openapi))
+        .route("/docs", get(handle_docs))
+        .layer(middleware::from_fn(propagate_request_id));
 
-    Ok(router.into())
+    Ok(RetrieverService(router))
 }
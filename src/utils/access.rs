@@ -0,0 +1,544 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    ANONYMOUS_BURST, ANONYMOUS_DAILY_QUOTA, ANONYMOUS_RATE_PER_SEC, API_KEY_BURST,
+    API_KEY_DAILY_QUOTA, API_KEY_RATE_PER_SEC, BUCKET_SWEEP_INTERVAL_SECS, TRUSTED_PROXIES,
+    USAGE_DB_PATH,
+};
+use anyhow::Error;
+use axum::extract::{ConnectInfo, MatchedPath, Request};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rusqlite::{params, Connection, TransactionBehavior};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::Span;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Request allowance of a class of clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tier {
+    /// Sustained requests per second.
+    pub rate_per_sec: f64,
+    /// Requests that may be made at once after being idle.
+    pub burst: f64,
+    /// Requests per UTC day, 0 for unlimited.
+    pub daily_quota: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessConfig {
+    pub anonymous: Option<Tier>,
+    pub api_key: Tier,
+    /// secret -> key id
+    pub keys: HashMap<String, String>,
+    pub admin_token: Option<String>,
+    /// Proxies in front of the service, each appending the address it got the
+    /// request from to `x-forwarded-for`.
+    pub trusted_proxies: usize,
+}
+
+impl AccessConfig {
+    /// Keys come from `WVM_API_KEYS` as comma separated `id:secret` pairs.
+    /// Setting `WVM_ANONYMOUS_ACCESS=false` makes a key mandatory.
+    /// `WVM_TRUSTED_PROXIES` is the number of proxies in front of the service.
+    pub fn from_env() -> Self {
+        let keys = env_or("WVM_API_KEYS", String::new())
+            .split(',')
+            .filter_map(|entry| {
+                let (id, secret) = entry.trim().split_once(':')?;
+                Some((secret.to_string(), id.to_string()))
+            })
+            .filter(|(secret, id)| !secret.is_empty() && !id.is_empty())
+            .collect();
+        let anonymous = env_or("WVM_ANONYMOUS_ACCESS", true).then(|| Tier {
+            rate_per_sec: env_or("WVM_ANONYMOUS_RATE_PER_SEC", ANONYMOUS_RATE_PER_SEC),
+            burst: env_or("WVM_ANONYMOUS_BURST", ANONYMOUS_BURST),
+            daily_quota: env_or("WVM_ANONYMOUS_DAILY_QUOTA", ANONYMOUS_DAILY_QUOTA),
+        });
+        AccessConfig {
+            anonymous,
+            api_key: Tier {
+                rate_per_sec: env_or("WVM_API_KEY_RATE_PER_SEC", API_KEY_RATE_PER_SEC),
+                burst: env_or("WVM_API_KEY_BURST", API_KEY_BURST),
+                daily_quota: env_or("WVM_API_KEY_DAILY_QUOTA", API_KEY_DAILY_QUOTA),
+            },
+            keys,
+            admin_token: std::env::var("WVM_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            trusted_proxies: env_or("WVM_TRUSTED_PROXIES", TRUSTED_PROXIES),
+        }
+    }
+}
+
+static CONFIG: LazyLock<AccessConfig> = LazyLock::new(AccessConfig::from_env);
+
+/// Who a request is accounted to: `key:<id>` or `ip:<address>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Key(String),
+    Anonymous(String),
+}
+
+impl Client {
+    pub fn ledger_id(&self) -> String {
+        match self {
+            Client::Key(id) => format!("key:{}", id),
+            Client::Anonymous(ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tier: Tier,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(tier: Tier, now: Instant) -> Self {
+        Bucket {
+            tier,
+            tokens: tier.burst,
+            updated: now,
+        }
+    }
+
+    /// Tokens in the bucket at `now`, capped at the burst.
+    fn refilled(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.tier.rate_per_sec).min(self.tier.burst)
+    }
+
+    /// Takes a token if one is available, otherwise returns how many seconds
+    /// until the next one.
+    fn take(&mut self, now: Instant) -> Result<(), f64> {
+        self.tokens = self.refilled(now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - self.tokens) / self.tier.rate_per_sec)
+        }
+    }
+}
+
+static BUCKETS: LazyLock<Mutex<HashMap<Client, Bucket>>> = LazyLock::new(Default::default);
+
+fn take_token(client: &Client, tier: &Tier) -> Result<(), f64> {
+    let now = Instant::now();
+    BUCKETS
+        .lock()
+        .unwrap()
+        .entry(client.clone())
+        .or_insert_with(|| Bucket::full(*tier, now))
+        .take(now)
+}
+
+/// Drops the buckets that refilled completely, which are the same as no
+/// bucket at all.
+fn sweep_buckets(buckets: &mut HashMap<Client, Bucket>, now: Instant) {
+    // each bucket refills at the rate of its own client's tier
+    buckets.retain(|_, bucket| bucket.refilled(now) < bucket.tier.burst);
+}
+
+/// Sweeps the rate limiter's buckets every `WVM_BUCKET_SWEEP_INTERVAL_SECS`,
+/// off the request path.
+pub async fn run_bucket_sweep() {
+    let interval = env_or("WVM_BUCKET_SWEEP_INTERVAL_SECS", BUCKET_SWEEP_INTERVAL_SECS);
+    let mut ticks = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        ticks.tick().await;
+        sweep_buckets(&mut BUCKETS.lock().unwrap(), Instant::now());
+    }
+}
+
+/// Per client, per UTC day, per route request counts. Kept in SQLite so
+/// quotas and exports survive restarts.
+static LEDGER: LazyLock<Mutex<Connection>> = LazyLock::new(|| {
+    // tests must not see, nor fill, the ledger of the host they run on
+    let conn = if cfg!(test) {
+        Connection::open_in_memory()
+    } else {
+        Connection::open(env_or("WVM_USAGE_DB_PATH", USAGE_DB_PATH.to_string()))
+    }
+    .expect("could not open the usage ledger");
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        CREATE TABLE IF NOT EXISTS usage (
+            client TEXT NOT NULL,
+            day INTEGER NOT NULL,
+            route TEXT NOT NULL,
+            requests INTEGER NOT NULL,
+            PRIMARY KEY (client, day, route)
+        );",
+    )
+    .expect("could not initialize the usage ledger");
+    Mutex::new(conn)
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct UsageRecord {
+    /// UTC date, `YYYY-MM-DD`.
+    pub date: String,
+    pub route: String,
+    pub requests: u64,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400
+}
+
+fn seconds_until_tomorrow() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    86_400 - now % 86_400
+}
+
+/// Counts a request of `client` to `route`, unless the client already used
+/// up `daily_quota` today (0 for unlimited). Returns whether it was counted.
+/// The check and the count are one transaction, so concurrent requests can't
+/// both take the last request of a quota.
+fn count_request(client: &str, route: &str, daily_quota: u64) -> Result<bool, Error> {
+    let mut conn = LEDGER.lock().unwrap();
+    let db_tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let day = today();
+    if daily_quota > 0 {
+        let used: u64 = db_tx.query_row(
+            "SELECT COALESCE(SUM(requests), 0) FROM usage WHERE client = ?1 AND day = ?2",
+            params![client, day],
+            |row| row.get(0),
+        )?;
+        if used >= daily_quota {
+            return Ok(false);
+        }
+    }
+    db_tx.execute(
+        "INSERT INTO usage (client, day, route, requests) VALUES (?1, ?2, ?3, 1)
+        ON CONFLICT (client, day, route) DO UPDATE SET requests = requests + 1",
+        params![client, day, route],
+    )?;
+    db_tx.commit()?;
+    Ok(true)
+}
+
+/// Usage ledger of one API key id, oldest day first.
+pub fn usage_of_key(key_id: &str) -> Result<Vec<UsageRecord>, Error> {
+    let conn = LEDGER.lock().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT day, route, requests FROM usage WHERE client = ?1 ORDER BY day, route")?;
+    let rows = stmt.query_map(params![format!("key:{}", key_id)], |row| {
+        Ok(UsageRecord {
+            date: civil_date(row.get(0)?),
+            route: row.get(1)?,
+            requests: row.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// `YYYY-MM-DD` of a day counted from the unix epoch.
+fn civil_date(days: u64) -> String {
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| bearer(headers))
+        .map(str::trim)
+}
+
+/// Address of the client behind `trusted_proxies` proxies. The hops of
+/// `x-forwarded-for` left of the ones our proxies appended are set by the
+/// client, so the client is the rightmost hop not added by a trusted proxy.
+/// Without proxies it is the peer of the connection.
+fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trusted_proxies: usize) -> String {
+    let peer = peer.map_or_else(|| "unknown".to_string(), |peer| peer.ip().to_string());
+    if trusted_proxies == 0 {
+        return peer;
+    }
+    // the peer is the last trusted proxy
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty());
+    let hops: Vec<&str> = forwarded.chain([peer.as_str()]).collect();
+    // a shorter chain didn't come through every proxy, its leftmost hop was
+    // still appended by one of ours
+    hops[hops.len().saturating_sub(trusted_proxies + 1)].to_string()
+}
+
+/// Resolves the client of a request from its headers and the peer address
+/// of its connection, `Err` with the rejection when its key is unknown or
+/// anonymous access is off.
+pub fn identify(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+) -> Result<(Client, Tier), (StatusCode, &'static str)> {
+    let config = &*CONFIG;
+    match presented_key(headers) {
+        Some(secret) => match config.keys.get(secret) {
+            Some(id) => Ok((Client::Key(id.clone()), config.api_key)),
+            None => Err((StatusCode::UNAUTHORIZED, "unknown API key")),
+        },
+        None => match config.anonymous {
+            Some(tier) => Ok((
+                Client::Anonymous(client_ip(headers, peer, config.trusted_proxies)),
+                tier,
+            )),
+            None => Err((StatusCode::UNAUTHORIZED, "an API key is required")),
+        },
+    }
+}
+
+fn too_many_requests(message: &str, retry_after_secs: u64) -> Response {
+    let mut res = (StatusCode::TOO_MANY_REQUESTS, message.to_string()).into_response();
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs.max(1)));
+    res
+}
+
+/// Axum middleware authenticating API keys, applying the token bucket and
+/// daily quota of the client's tier and recording the request in the usage
+/// ledger. Anonymous clients are told apart by the peer address the server
+/// was set up to pass as `ConnectInfo`.
+pub async fn enforce_access(req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let (client, tier) = match identify(req.headers(), peer) {
+        Ok(identified) => identified,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if let Err(wait_secs) = take_token(&client, &tier) {
+        return too_many_requests("rate limit exceeded", wait_secs.ceil() as u64);
+    }
+
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    let ledger_id = client.ledger_id();
+    let span = Span::current();
+    let counted = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| count_request(&ledger_id, &route, tier.daily_quota))
+    })
+    .await;
+    // an unusable ledger doesn't lock clients out
+    if let Ok(Ok(false)) = counted {
+        return too_many_requests("daily quota exceeded", seconds_until_tomorrow());
+    }
+
+    next.run(req).await
+}
+
+/// Whether the request carries the admin token as its bearer token.
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    match (&CONFIG.admin_token, bearer(headers)) {
+        (Some(admin_token), Some(presented)) => constant_time_eq(admin_token, presented),
+        _ => false,
+    }
+}
+
+/// Compares the digests of both secrets, so the time taken depends on
+/// neither their contents nor their lengths.
+fn constant_time_eq(expected: &str, presented: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let presented = Sha256::digest(presented.as_bytes());
+    expected
+        .iter()
+        .zip(presented.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// The usage of the calling key, or with the admin token, of `key_id`.
+pub fn authorized_usage(headers: &HeaderMap, key_id: Option<&str>) -> Response {
+    let key_id = match key_id {
+        Some(key_id) => {
+            if !is_admin(headers) {
+                return (StatusCode::FORBIDDEN, "admin token required").into_response();
+            }
+            key_id.to_string()
+        }
+        // only whether there is a key matters, not the caller's address
+        None => match identify(headers, None) {
+            Ok((Client::Key(id), _)) => id,
+            Ok((Client::Anonymous(_), _)) => {
+                return (StatusCode::UNAUTHORIZED, "an API key is required").into_response()
+            }
+            Err(rejection) => return rejection.into_response(),
+        },
+    };
+    match usage_of_key(&key_id) {
+        Ok(usage) => axum::Json(usage).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let tier = Tier {
+            rate_per_sec: 2.0,
+            burst: 3.0,
+            daily_quota: 0,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::full(tier, start);
+        for _ in 0..3 {
+            assert!(bucket.take(start).is_ok());
+        }
+        let wait = bucket.take(start).unwrap_err();
+        assert!((wait - 0.5).abs() < 1e-9);
+
+        assert!(bucket.take(start + Duration::from_millis(500)).is_ok());
+        assert!(bucket.take(start + Duration::from_millis(500)).is_err());
+        // refills up to the burst only
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take(later).is_ok());
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_buckets_refill_at_their_own_rate() {
+        let slow = Tier {
+            rate_per_sec: 1.0,
+            burst: 10.0,
+            daily_quota: 0,
+        };
+        let fast = Tier {
+            rate_per_sec: 100.0,
+            ..slow
+        };
+        let start = Instant::now();
+        let mut slow_bucket = Bucket::full(slow, start);
+        let mut fast_bucket = Bucket::full(fast, start);
+        for _ in 0..10 {
+            assert!(slow_bucket.take(start).is_ok());
+            assert!(fast_bucket.take(start).is_ok());
+        }
+        let later = start + Duration::from_millis(100);
+        assert!(slow_bucket.refilled(later) < slow.burst);
+        assert_eq!(fast_bucket.refilled(later), fast.burst);
+    }
+
+    #[test]
+    fn test_sweep_drops_full_buckets() {
+        let tier = Tier {
+            rate_per_sec: 1.0,
+            burst: 2.0,
+            daily_quota: 0,
+        };
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        let mut busy = Bucket::full(tier, start);
+        busy.take(start).unwrap();
+        buckets.insert(Client::Key("busy".to_string()), busy);
+        buckets.insert(Client::Key("idle".to_string()), Bucket::full(tier, start));
+        sweep_buckets(&mut buckets, start);
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key(&Client::Key("busy".to_string())));
+        sweep_buckets(&mut buckets, start + Duration::from_secs(1));
+        assert!(buckets.is_empty());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 3.3.3.3"),
+        );
+        // without proxies, forwarded hops are the client's own say
+        assert_eq!(client_ip(&headers, Some(peer), 0), "10.0.0.1");
+        assert_eq!(client_ip(&headers, None, 0), "unknown");
+        // the rightmost hop not appended by a trusted proxy
+        assert_eq!(client_ip(&headers, Some(peer), 1), "3.3.3.3");
+        assert_eq!(client_ip(&headers, Some(peer), 2), "2.2.2.2");
+        assert_eq!(client_ip(&headers, Some(peer), 5), "1.1.1.1");
+        assert_eq!(client_ip(&HeaderMap::new(), Some(peer), 1), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_quota_is_checked_and_counted_at_once() {
+        let counted = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| count_request("key:quota-test", "/v1/calldata", 5)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap().unwrap_or(false))
+                .filter(|counted| *counted)
+                .count()
+        });
+        assert_eq!(counted, 5);
+        assert!(!count_request("key:quota-test", "/v1/war-calldata", 5).unwrap());
+        assert_eq!(usage_of_key("quota-test").unwrap()[0].requests, 5);
+        // unlimited tiers are counted too
+        assert!(count_request("key:quota-test", "/v1/calldata", 0).unwrap());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("s3cret", "s3cret"));
+        assert!(!constant_time_eq("s3cret", "s3cres"));
+        assert!(!constant_time_eq("s3cret", "s3cret "));
+        assert!(!constant_time_eq("s3cret", ""));
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), "1970-01-01");
+        assert_eq!(civil_date(19_782), "2024-02-29");
+        assert_eq!(civil_date(20_454), "2026-01-01");
+    }
+}
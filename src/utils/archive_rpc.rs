@@ -0,0 +1,430 @@
+use crate::utils::arweave::get_block_from_arweave;
+use crate::utils::arweave_gql::retrieve_block_from_arweave;
+use crate::utils::cache::get_cached_block_by_number;
+use crate::utils::index::{
+    get_archive_id, get_block_number, get_latest_block_number, get_tx_location, record_archive_id,
+    record_block,
+};
+use crate::utils::schemas::{ArchivedTransaction, Block};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    pub jsonrpc: &'static str,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Serves a JSON-RPC payload (single call or batch) from archived blocks.
+pub async fn handle_rpc_payload(payload: Value) -> Value {
+    match payload {
+        Value::Array(calls) => {
+            let mut responses = Vec::with_capacity(calls.len());
+            for call in calls {
+                responses.push(handle_rpc_call(call).await);
+            }
+            serde_json::to_value(responses).unwrap()
+        }
+        call => serde_json::to_value(handle_rpc_call(call).await).unwrap(),
+    }
+}
+
+async fn handle_rpc_call(call: Value) -> RpcResponse {
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let outcome = match serde_json::from_value::<RpcRequest>(call) {
+        Ok(req) => dispatch(&req.method, &req.params).await,
+        Err(e) => Err(RpcError::new(INVALID_REQUEST, e.to_string())),
+    };
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result,
+        error,
+    }
+}
+
+async fn dispatch(method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    match method {
+        "eth_getTransactionByHash" => {
+            let txid = param_str(params, 0)?;
+            let Some(location) = get_tx_location(txid).await else {
+                return Ok(Value::Null);
+            };
+            let block = get_block_from_arweave(&location.arweave_id)
+                .await
+                .map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))?;
+            Ok(transaction_to_rpc(&block, location.index as usize))
+        }
+        "eth_getBlockByNumber" => {
+            let number = parse_block_number(param_str(params, 0)?).await?;
+            let full = param_bool(params, 1)?;
+            let block = load_archived_block(number).await?;
+            Ok(block.map_or(Value::Null, |block| block_to_rpc(&block, full)))
+        }
+        "eth_getBlockByHash" => {
+            let Some(number) = get_block_number(param_str(params, 0)?).await else {
+                return Ok(Value::Null);
+            };
+            let full = param_bool(params, 1)?;
+            let block = load_archived_block(number).await?;
+            Ok(block.map_or(Value::Null, |block| block_to_rpc(&block, full)))
+        }
+        "eth_getTransactionByBlockNumberAndIndex" => {
+            let number = parse_block_number(param_str(params, 0)?).await?;
+            let index = parse_quantity(param_str(params, 1)?)?;
+            let block = load_archived_block(number).await?;
+            Ok(block.map_or(Value::Null, |block| {
+                transaction_to_rpc(&block, index as usize)
+            }))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("the method {} does not exist/is not available", method),
+        )),
+    }
+}
+
+/// Resolves the block's archive through `eth_getArweaveStorageProof`, falling
+/// back to the retriever's index when the WeaveVM RPC can't answer.
+pub async fn load_archived_block(number: u64) -> Result<Option<Arc<Block>>, RpcError> {
+    if let Some(block) = get_cached_block_by_number(number) {
+        return Ok(Some(block));
+    }
+
+    let from_rpc = match u32::try_from(number) {
+        Ok(number) => retrieve_block_from_arweave(number).await.ok(),
+        Err(_) => None,
+    };
+    let arweave_id = match from_rpc.filter(|id| !id.is_empty()) {
+        Some(id) => {
+            let _ = record_archive_id(number, &id).await;
+            id
+        }
+        None => match get_archive_id(number).await {
+            Some(id) => id,
+            None => return Ok(None),
+        },
+    };
+    let block = get_block_from_arweave(&arweave_id)
+        .await
+        .map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))?;
+    let _ = record_block(number, &arweave_id, &block).await;
+    Ok(Some(block))
+}
+
+fn block_to_rpc(block: &Block, full: bool) -> Value {
+    let transactions: Vec<Value> = if full {
+        (0..block.transactions.len())
+            .map(|i| transaction_to_rpc(block, i))
+            .collect()
+    } else {
+        block
+            .transactions
+            .iter()
+            .map(|tx| Value::from(tx.hash.to_string()))
+            .collect()
+    };
+    let withdrawals = block.withdrawals.as_ref().map(|withdrawals| {
+        withdrawals
+            .iter()
+            .map(|withdrawal| {
+                json!({
+                    "index": to_quantity(Some(&withdrawal.index)),
+                    "validatorIndex": to_quantity(Some(&withdrawal.validator_index)),
+                    "address": withdrawal.address,
+                    "amount": to_quantity(Some(&withdrawal.amount)),
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // totalDifficulty is left out: it can't be derived from a single archived
+    // header, and execution-apis no longer lists it
+    json!({
+        "number": to_quantity(block.number.as_deref()),
+        "hash": block.hash,
+        "parentHash": block.parent_hash,
+        "nonce": to_nonce(block.nonce.as_deref()),
+        "mixHash": block.mix_hash,
+        "sha3Uncles": block.sha3_uncles,
+        "logsBloom": block.logs_bloom,
+        "stateRoot": block.state_root,
+        "receiptsRoot": block.receipts_root,
+        "miner": block.miner,
+        "difficulty": to_quantity(block.difficulty.as_deref()),
+        "extraData": block.extra_data,
+        "size": to_quantity(block.size.as_deref()),
+        "gasLimit": to_quantity(block.gas_limit.as_deref()),
+        "gasUsed": to_quantity(block.gas_used.as_deref()),
+        "timestamp": to_quantity(block.timestamp.as_deref()),
+        "baseFeePerGas": to_quantity(block.base_fee_per_gas.as_deref()),
+        "blobGasUsed": to_quantity(block.blob_gas_used.as_deref()),
+        "excessBlobGas": to_quantity(block.excess_blob_gas.as_deref()),
+        "parentBeaconBlockRoot": block.parent_beacon_block_root,
+        "transactionsRoot": block.transactions_root,
+        "withdrawalsRoot": block.withdrawals_root,
+        "withdrawals": withdrawals,
+        "transactions": transactions,
+        "uncles": [],
+    })
+}
+
+fn transaction_to_rpc(block: &Block, index: usize) -> Value {
+    let Some(tx): Option<&ArchivedTransaction> = block.transactions.get(index) else {
+        return Value::Null;
+    };
+    let parity = u64::from(tx.y_parity);
+    let v = match (tx.tx_type.as_str(), tx.chain_id.as_deref()) {
+        // EIP-155 replay protected legacy tx
+        ("0", Some(chain_id)) => U256::from_dec_str(chain_id)
+            .ok()
+            .map(|chain_id| chain_id * 2 + 35 + parity),
+        ("0", None) => Some(U256::from(27 + parity)),
+        _ => Some(U256::from(parity)),
+    };
+
+    json!({
+        "blockHash": block.hash,
+        "blockNumber": to_quantity(block.number.as_deref()),
+        "transactionIndex": format!("{:#x}", index),
+        "hash": tx.hash,
+        "from": tx.from,
+        "to": tx.to,
+        "nonce": to_quantity(Some(&tx.nonce)),
+        "value": to_quantity(Some(&tx.value)),
+        "gas": to_quantity(Some(&tx.gas)),
+        "input": tx.input,
+        "type": to_quantity(Some(&tx.tx_type)),
+        "chainId": to_quantity(tx.chain_id.as_deref()),
+        "gasPrice": to_quantity(tx.gas_price.as_deref()),
+        "maxFeePerGas": to_quantity(tx.max_fee_per_gas.as_deref()),
+        "maxPriorityFeePerGas": to_quantity(tx.max_priority_fee_per_gas.as_deref()),
+        "accessList": tx.access_list,
+        "v": v.map(|v| format!("{:#x}", v)),
+        "r": to_quantity(Some(&tx.r)),
+        "s": to_quantity(Some(&tx.s)),
+        "yParity": format!("{:#x}", parity),
+    })
+}
+
+/// Archived blocks keep numbers as decimal strings, JSON-RPC wants hex quantities.
+fn to_quantity(decimal: Option<&str>) -> Option<String> {
+    let value = U256::from_dec_str(decimal?).ok()?;
+    Some(format!("{:#x}", value))
+}
+
+/// Block nonces are 8-byte DATA rather than quantities.
+fn to_nonce(decimal: Option<&str>) -> Option<String> {
+    let value = decimal?.parse::<u64>().ok()?;
+    Some(format!("{:#018x}", value))
+}
+
+fn parse_quantity(hex: &str) -> Result<u64, RpcError> {
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+        .map_err(|_| RpcError::new(INVALID_PARAMS, format!("invalid quantity: {}", hex)))
+}
+
+async fn parse_block_number(tag: &str) -> Result<u64, RpcError> {
+    match tag {
+        "earliest" => Ok(0),
+        // without the RPC head, the newest archive we know of is the best answer
+        "latest" | "safe" | "finalized" => get_latest_block_number()
+            .await
+            .ok_or_else(|| RpcError::new(SERVER_ERROR, "no archived block indexed yet")),
+        "pending" => Err(RpcError::new(
+            INVALID_PARAMS,
+            "pending blocks are not archived",
+        )),
+        number => parse_quantity(number),
+    }
+}
+
+fn param_str(params: &[Value], position: usize) -> Result<&str, RpcError> {
+    params.get(position).and_then(Value::as_str).ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("missing or invalid param at position {}", position),
+        )
+    })
+}
+
+fn param_bool(params: &[Value], position: usize) -> Result<bool, RpcError> {
+    match params.get(position) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(full)) => Ok(*full),
+        Some(_) => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("invalid bool param at position {}", position),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_quantity() {
+        assert_eq!(to_quantity(Some("0")), Some("0x0".to_string()));
+        assert_eq!(to_quantity(Some("111777")), Some("0x1b4a1".to_string()));
+        assert_eq!(to_quantity(Some("not a number")), None);
+        assert_eq!(to_quantity(None), None);
+    }
+
+    #[test]
+    fn test_block_encodings() {
+        use crate::utils::schemas::ArchivedWithdrawal;
+        use reth_primitives::{Address, Bytes, B256};
+        use std::str::FromStr;
+
+        let hash =
+            B256::from_str("0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a")
+                .unwrap();
+        // a signed EIP-1559 transfer as mined on WeaveVM
+        let tx = ArchivedTransaction {
+            hash,
+            from: Some(Address::from_str("0x9965507D1a55bcC2695C58ba16FB37d819B0A4dc").unwrap()),
+            to: Some(Address::from_str("0x976ea74026e726554db657fa54763abd0c3a0aa9").unwrap()),
+            nonce: "3".to_string(),
+            value: "100000000000000000".to_string(),
+            gas: "3000000".to_string(),
+            input: Bytes::new(),
+            tx_type: "2".to_string(),
+            chain_id: Some("111777".to_string()),
+            gas_price: Some("1000000007".to_string()),
+            max_fee_per_gas: Some("2000000000".to_string()),
+            max_priority_fee_per_gas: Some("1000000000".to_string()),
+            access_list: Some(Vec::new()),
+            r: "60863783783190709895219745296417521889550657502367005072725049532278108435464"
+                .to_string(),
+            s: "55304164041344809980374105818096047390785772674235144761567127334139331522912"
+                .to_string(),
+            y_parity: true,
+        };
+        let block = Block {
+            base_fee_per_gas: Some("7".to_string()),
+            blob_gas_used: Some("0".to_string()),
+            difficulty: Some("0".to_string()),
+            excess_blob_gas: Some("0".to_string()),
+            extra_data: Some(Bytes::new()),
+            gas_limit: Some("300000000".to_string()),
+            gas_used: Some("21000".to_string()),
+            hash: Some(B256::repeat_byte(0x11)),
+            logs_bloom: Some(format!("0x{}", "00".repeat(256))),
+            miner: Some("0xa2a0d977847805fe224b789d8c4d3d711ab251e7".to_string()),
+            mix_hash: Some(B256::ZERO),
+            nonce: Some("0".to_string()),
+            number: Some("1024".to_string()),
+            parent_beacon_block_root: Some(B256::ZERO),
+            parent_hash: Some(B256::repeat_byte(0x22)),
+            receipts_root: Some(B256::repeat_byte(0x33)),
+            seal_fields: Vec::new(),
+            sha3_uncles: Some(
+                "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347".to_string(),
+            ),
+            size: Some("700".to_string()),
+            state_root: Some(B256::repeat_byte(0x44)),
+            timestamp: Some("1718000000".to_string()),
+            total_difficulty: None,
+            transactions_root: Some(B256::repeat_byte(0x55)),
+            withdrawals_root: Some(B256::repeat_byte(0x66)),
+            withdrawals: Some(vec![ArchivedWithdrawal {
+                index: "16".to_string(),
+                validator_index: "5".to_string(),
+                address: Address::repeat_byte(0x77),
+                amount: "1000".to_string(),
+            }]),
+            transactions_and_calldata: vec![(hash, Bytes::new())],
+            transactions: vec![tx],
+        };
+
+        let value = block_to_rpc(&block, true);
+        assert_eq!(value["nonce"], "0x0000000000000000");
+        assert_eq!(value["miner"], "0xa2a0d977847805fe224b789d8c4d3d711ab251e7");
+        assert!(value.get("totalDifficulty").is_none());
+        assert_eq!(value["transactions"][0]["v"], "0x1");
+        assert_eq!(value["transactions"][0]["yParity"], "0x1");
+
+        // what ethers-based clients parse the response into
+        let parsed: ethers::types::Block<ethers::types::Transaction> =
+            serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.number, Some(1024.into()));
+        assert_eq!(parsed.withdrawals.unwrap()[0].amount, 1000.into());
+        let tx = &parsed.transactions[0];
+        assert_eq!(tx.max_fee_per_gas, Some(2_000_000_000u64.into()));
+        // re-hashing and recovering only work when every signed field survived
+        assert_eq!(format!("{:#x}", tx.hash()), format!("{:#x}", hash));
+        assert_eq!(
+            format!("{:#x}", tx.recover_from().unwrap()),
+            "0x9965507d1a55bcc2695c58ba16fb37d819b0a4dc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method_and_batch() {
+        let payload = json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": [] },
+            { "jsonrpc": "2.0", "id": 2, "method": "eth_getBlockByNumber", "params": [] },
+        ]);
+        let res = handle_rpc_payload(payload).await;
+
+        assert_eq!(res[0]["id"], 1);
+        assert_eq!(res[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(res[1]["id"], 2);
+        assert_eq!(res[1]["error"]["code"], INVALID_PARAMS);
+        assert!(res[1].get("result").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unindexed_tx_is_null() {
+        let payload = json!({
+            "jsonrpc": "2.0",
+            "id": "a",
+            "method": "eth_getTransactionByHash",
+            "params": ["0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a"],
+        });
+        let res = handle_rpc_payload(payload).await;
+
+        assert_eq!(res["id"], "a");
+        assert_eq!(res["result"], Value::Null);
+    }
+}
@@ -1,43 +1,463 @@
+use crate::utils::cache::{
+    cache_block, cache_tx_hash_check, get_cached_block, get_cached_tx_hash_check,
+};
+use crate::utils::cache_backend::{archive_key, cache_backend};
+use crate::utils::config::StageTimeouts;
 use crate::utils::constants::ARWEAVE_GATEWAY_URL;
+use crate::utils::hedge::{hedge_config, hedged};
+use crate::utils::limits::{limited_brotli_reader, DecodeLimits, LimitError};
+use crate::utils::metrics::{observe_decode, observe_payload};
+use crate::utils::provenance::{record_gateway, record_stage, CacheOutcome, Stage, StageTiming};
 use crate::utils::schemas::{Block, EncodingUtils};
+use crate::utils::single_flight::{FlightError, SingleFlight};
+use crate::utils::telemetry::traced;
+use crate::utils::upstream::{
+    call_upstream_holding_slot, http_client, is_upstream_rejection, UpstreamSlot,
+};
+use anyhow::Error;
+use futures::TryStreamExt;
 use reqwest;
+use reth_primitives::{Bytes, SealedBlockWithSenders, B256};
+use std::future::Future;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{Instrument, Span};
 
 pub async fn get_tx_calldata_from_arweave(
     ar_txid: &str,
     wvm_txid: String,
 ) -> Result<String, String> {
-    let req = format!("{}/{}", ARWEAVE_GATEWAY_URL, ar_txid);
-    let req = reqwest::get(req).await;
-
-    let data = match req {
-        Ok(res) => {
-            if res.status().is_success() {
-                res.bytes().await.unwrap_or(bytes::Bytes::new())
-            } else {
-                bytes::Bytes::new()
+    let Ok(wvm_txid) = B256::from_str(wvm_txid.trim()) else {
+        return Err(String::from("Error retrieving calldata from Arweave"));
+    };
+    match find_tx_calldata_in_archive(ar_txid, wvm_txid).await {
+        Ok(Some(calldata)) => Ok(calldata.to_string()),
+        Ok(None) => Err(String::from("Error retrieving calldata from Arweave")),
+        Err(e) if is_upstream_rejection(&e) => Err(e.to_string()),
+        Err(_) => Ok("0x".to_string()),
+    }
+}
+
+type ArchiveStream = Result<(Arc<SealedBlockWithSenders>, ArchiveProvenance), FlightError>;
+
+static ARCHIVE_STREAMS: LazyLock<SingleFlight<String, ArchiveStream>> =
+    LazyLock::new(Default::default);
+
+/// Where an archive came from and how long its stages took, handed to every
+/// request waiting on the same download.
+#[derive(Debug, Clone, Default)]
+struct ArchiveProvenance {
+    gateway: Option<String>,
+    stages: Vec<StageTiming>,
+}
+static BLOCK_CONVERSIONS: LazyLock<SingleFlight<String, Result<Arc<Block>, String>>> =
+    LazyLock::new(Default::default);
+
+/// Calldata of `wvm_txid` in the archive `ar_txid`, `None` when the archive
+/// doesn't contain it.
+///
+/// A cached block is scanned directly. Otherwise the whole archived block is
+/// decoded while it streams in, then scanned; the full `Block` for the cache
+/// is built from it in the background, off the response path.
+#[tracing::instrument]
+pub async fn find_tx_calldata_in_archive(
+    ar_txid: &str,
+    wvm_txid: B256,
+) -> Result<Option<Bytes>, Error> {
+    let started = Instant::now();
+    if let Some(block) = get_cached_block(ar_txid) {
+        // a cached block skips the download and both decode stages
+        for stage in [Stage::Download, Stage::Decompress, Stage::Decode] {
+            record_stage(StageTiming::since(stage, Some(CacheOutcome::Hit), started));
+        }
+        return Ok(block
+            .transactions_and_calldata
+            .iter()
+            .find(|(hash, _)| *hash == wvm_txid)
+            .map(|(_, calldata)| calldata.clone()));
+    }
+
+    let (sealed, provenance) = stream_sealed_block(ar_txid).await?;
+    if let Some(gateway) = &provenance.gateway {
+        record_gateway(gateway);
+    }
+    provenance.stages.into_iter().for_each(record_stage);
+    let calldata = find_tx_calldata(&sealed, wvm_txid);
+    let ar_txid = ar_txid.to_string();
+    tokio::spawn(async move { block_from_sealed(&ar_txid, sealed).await }.in_current_span());
+    Ok(calldata)
+}
+
+/// Whether `wvm_txid` in the archive `ar_txid`, re-hashed from its signed
+/// encoding, still hashes to `wvm_txid`. `None` when the archive doesn't
+/// contain it. Cached blocks don't keep the signed encoding, so the archive
+/// is decoded again the first time; the answer is cached since a mismatching
+/// tx keeps being asked for.
+#[tracing::instrument]
+pub async fn archived_tx_hash_matches(
+    ar_txid: &str,
+    wvm_txid: B256,
+) -> Result<Option<bool>, Error> {
+    if let Some(matches) = get_cached_tx_hash_check(ar_txid, wvm_txid) {
+        return Ok(matches);
+    }
+    let (sealed, _) = stream_sealed_block(ar_txid).await?;
+    let matches = sealed
+        .block
+        .body
+        .transactions()
+        .find(|tx| tx.hash == wvm_txid)
+        .map(|tx| tx.recalculate_hash() == wvm_txid);
+    cache_tx_hash_check(ar_txid, wvm_txid, matches);
+    Ok(matches)
+}
+
+/// Scans an archived block for `txid`, stopping at the first match.
+pub fn find_tx_calldata(block: &SealedBlockWithSenders, txid: B256) -> Option<Bytes> {
+    block
+        .block
+        .body
+        .transactions()
+        .find(|tx| tx.hash == txid)
+        .map(|tx| tx.transaction.input().clone())
+}
+
+#[tracing::instrument]
+pub async fn get_block_from_arweave(ar_txid: &str) -> Result<Arc<Block>, Error> {
+    if let Some(block) = get_cached_block(ar_txid) {
+        return Ok(block);
+    }
+
+    let (sealed, _) = stream_sealed_block(ar_txid).await?;
+    block_from_sealed(ar_txid, sealed).await
+}
+
+/// Like `get_block_from_arweave`, but leaves the block cache alone. For bulk
+/// walks over the archive, like the index sync, that would otherwise evict
+/// the blocks requests are served from.
+#[tracing::instrument]
+pub async fn fetch_block_from_arweave(ar_txid: &str) -> Result<Arc<Block>, Error> {
+    if let Some(block) = get_cached_block(ar_txid) {
+        return Ok(block);
+    }
+
+    let (sealed, _) = stream_sealed_block(ar_txid).await?;
+    convert_sealed(sealed)
+        .await
+        .map(Arc::new)
+        .map_err(Error::msg)
+}
+
+/// Converts a decoded archive into the cached `Block` representation, once per
+/// archive even when several requests race for it.
+#[tracing::instrument(skip(sealed))]
+async fn block_from_sealed(
+    ar_txid: &str,
+    sealed: Arc<SealedBlockWithSenders>,
+) -> Result<Arc<Block>, Error> {
+    let ar_txid = ar_txid.to_string();
+    BLOCK_CONVERSIONS
+        .run(ar_txid.clone(), || async move {
+            let block = Arc::new(convert_sealed(sealed).await?);
+            cache_block(&ar_txid, block.clone());
+            Ok(block)
+        })
+        .await
+        .map_err(Error::msg)
+}
+
+/// `Block::from` off the executor, bounded by the decode timeout.
+async fn convert_sealed(sealed: Arc<SealedBlockWithSenders>) -> Result<Block, String> {
+    let timeouts = StageTimeouts::from_env();
+    let span = Span::current();
+    let convert =
+        tokio::task::spawn_blocking(move || span.in_scope(|| Block::from(sealed.as_ref())));
+    match timeout(timeouts.archive_decode, convert).await {
+        Ok(Ok(block)) => Ok(block),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("archive decode timed out".to_string()),
+    }
+}
+
+#[tracing::instrument]
+async fn stream_sealed_block(
+    ar_txid: &str,
+) -> Result<(Arc<SealedBlockWithSenders>, ArchiveProvenance), Error> {
+    let timeouts = StageTimeouts::from_env();
+    let ar_txid = ar_txid.to_string();
+    ARCHIVE_STREAMS
+        .run(ar_txid.clone(), || async move {
+            match timeout(timeouts.archive_download, stream_decode_archive(&ar_txid)).await {
+                Ok(Ok((sealed, provenance))) => Ok((Arc::new(sealed), provenance)),
+                Ok(Err(e)) => Err(FlightError::from(e)),
+                Err(_) => Err(FlightError::Other("archive download timed out".to_string())),
             }
+        })
+        .await
+        .map_err(Error::from)
+}
+
+/// Decodes the archive of `ar_txid` while it is being downloaded: gateway
+/// chunks go through brotli and borsh as they arrive, so neither the
+/// compressed nor the decompressed archive is ever held in full. Reading
+/// stops as soon as the block is decoded.
+///
+/// When a cache backend is configured the compressed bytes are kept on the
+/// side and persisted once the archive decoded successfully.
+///
+/// Archives crossing the `DecodeLimits` fail with a `LimitError`.
+#[tracing::instrument]
+async fn stream_decode_archive(
+    ar_txid: &str,
+) -> Result<(SealedBlockWithSenders, ArchiveProvenance), Error> {
+    let limits = DecodeLimits::from_env();
+    let started = Instant::now();
+    let backend = cache_backend().await;
+    if let Some(backend) = &backend {
+        if let Ok(Some(entry)) = backend.get(&archive_key(ar_txid)).await {
+            observe_payload("archive", entry.value.len());
+            let download = StageTiming::since(Stage::Download, Some(CacheOutcome::Hit), started);
+            let span = Span::current();
+            let (sealed, decode_stages) = tokio::task::spawn_blocking(move || {
+                span.in_scope(|| decode_archive(entry.value.as_slice(), limits))
+            })
+            .await??;
+            let mut stages = vec![download];
+            stages.extend(decode_stages);
+            return Ok((
+                sealed,
+                ArchiveProvenance {
+                    gateway: None,
+                    stages,
+                },
+            ));
         }
-        Err(_) => bytes::Bytes::new(),
+    }
+
+    let capture = backend.is_some();
+    let (gateway, (download, sealed, decode_stages, raw)) =
+        download_archive(ar_txid, |res| async move {
+            let download = StageTiming::since(Stage::Download, Some(CacheOutcome::Miss), started);
+            let (sealed, decode_stages, raw) = decode_response(res, limits, capture).await?;
+            Ok((download, sealed, decode_stages, raw))
+        })
+        .await?;
+
+    if let (Some(backend), Some(raw)) = (&backend, raw) {
+        let _ = backend.put(&archive_key(ar_txid), &raw).await;
+    }
+    let mut stages = vec![download];
+    stages.extend(decode_stages);
+    Ok((
+        sealed,
+        ArchiveProvenance {
+            gateway: Some(gateway.to_string()),
+            stages,
+        },
+    ))
+}
+
+/// Streams a gateway response through `decode_archive`, keeping the
+/// compressed bytes when `capture` is set.
+async fn decode_response(
+    res: reqwest::Response,
+    limits: DecodeLimits,
+    capture: bool,
+) -> Result<(SealedBlockWithSenders, [StageTiming; 2], Option<Vec<u8>>), Error> {
+    if res
+        .content_length()
+        .is_some_and(|len| len > limits.max_compressed_bytes)
+    {
+        return Err(Error::new(LimitError::CompressedTooLarge {
+            limit: limits.max_compressed_bytes,
+        }));
+    }
+    let stream = res.bytes_stream().map_err(std::io::Error::other);
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+    let mut reader = CapturingReader {
+        inner: reader,
+        captured: capture.then(Vec::new),
+        read: 0,
+        cancelled,
     };
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let decoded = span.in_scope(|| decode_archive(&mut reader, limits));
+        observe_payload("archive", reader.read as usize);
+        decoded.map(|(sealed, stages)| (sealed, stages, reader.captured))
+    })
+    .await?
+}
 
-    let unbrotli = EncodingUtils::brotli_decompress(data.to_vec()).unwrap_or(vec![]);
-    if unbrotli.is_empty() {
-        return Ok(String::from("0x"));
+/// Downloads the archive of `ar_txid` and hands the response to `read`,
+/// hedged to `WVM_HEDGE_GATEWAY_URL` when configured, see `hedge`. The hedge
+/// races whole transfers, body included, so a gateway that answers quickly
+/// but then trickles the body gets hedged too; the losing transfer is
+/// dropped mid-body. Returns the gateway that won along with what `read`
+/// made of its response.
+#[tracing::instrument(skip(read))]
+async fn download_archive<T, F, Fut>(ar_txid: &str, read: F) -> Result<(&'static str, T), Error>
+where
+    F: Fn(reqwest::Response) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let transfer = |gateway: &'static str| {
+        let read = &read;
+        async move {
+            let (gateway, res, slot) = fetch_archive(gateway, ar_txid).await?;
+            // the gateway's slot is taken until the body is read
+            let read = read(res).await?;
+            drop(slot);
+            Ok((gateway, read))
+        }
+    };
+    match &hedge_config().gateway {
+        Some(gateway) => hedged(transfer(ARWEAVE_GATEWAY_URL), || transfer(gateway.as_str())).await,
+        None => transfer(ARWEAVE_GATEWAY_URL).await,
     }
-    let unborsh = match EncodingUtils::borsh_deserialize(unbrotli) {
-        Ok(data) => data,
-        Err(_) => return Ok("0x".to_string())
+}
+
+/// Retries cover the response headers only, a body failing midway surfaces
+/// to the caller. The gateway's concurrency slot comes with the response, to
+/// be held while its body is read.
+async fn fetch_archive(
+    gateway: &'static str,
+    ar_txid: &str,
+) -> Result<(&'static str, reqwest::Response, UpstreamSlot), Error> {
+    let req = format!("{}/{}", gateway, ar_txid);
+    let (res, slot) = call_upstream_holding_slot(gateway, "archive", || async {
+        traced(http_client().get(&req))
+            .send()
+            .await?
+            .error_for_status()
+    })
+    .await?;
+    Ok((gateway, res, slot))
+}
+
+/// Streamed archives are decoded as they download, so for those the decode
+/// duration, and the `decompress` stage, include the transfer of the body.
+/// The `decode` stage is the rest: borsh decoding and sealing the block.
+#[tracing::instrument(skip_all)]
+fn decode_archive<R: Read>(
+    reader: R,
+    limits: DecodeLimits,
+) -> Result<(SealedBlockWithSenders, [StageTiming; 2]), Error> {
+    let started = Instant::now();
+    let mut decompressor = TimedReader {
+        inner: limited_brotli_reader(reader, limits),
+        elapsed: Duration::ZERO,
     };
-    //     println!("{:?}", unborsh.0);
-    let str_block = Block::from(unborsh);
-    let block_txs = str_block.transactions_and_calldata.to_vec();
-    let wvm_txid = wvm_txid.trim().to_lowercase(); // Normalize txid for comparison
+    let unborsh = EncodingUtils::borsh_deserialize_reader(&mut decompressor).map_err(|e| {
+        match LimitError::find(&e) {
+            Some(limit) => Error::new(limit),
+            None => Error::msg("empty or undecodable archive"),
+        }
+    });
+    observe_decode("archive", started);
+    let sealed = EncodingUtils::sealed_block_with_senders(unborsh?)?;
+    let stages = [
+        StageTiming::new(Stage::Decompress, None, decompressor.elapsed),
+        StageTiming::new(
+            Stage::Decode,
+            None,
+            started.elapsed().saturating_sub(decompressor.elapsed),
+        ),
+    ];
+    Ok((sealed, stages))
+}
+
+/// Adds up the time spent reading from `inner`.
+struct TimedReader<R> {
+    inner: R,
+    elapsed: Duration,
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let started = Instant::now();
+        let res = self.inner.read(buf);
+        self.elapsed += started.elapsed();
+        res
+    }
+}
+
+/// Raw archive reader that optionally keeps a copy of what it read and gives
+/// up once the request waiting for it is gone.
+struct CapturingReader<R> {
+    inner: R,
+    captured: Option<Vec<u8>>,
+    read: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for CapturingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("archive download cancelled"));
+        }
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if let Some(captured) = &mut self.captured {
+            captured.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+struct CancelOnDrop(Arc<AtomicBool>);
 
-    for (hash, calldata) in &block_txs {
-        if hash.trim().to_lowercase() == wvm_txid {
-            return Ok(calldata.clone());
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Raw (brotli-compressed) archive bytes, served from the persistent cache
+/// backend when one is configured. Archives are immutable, so a cached copy
+/// never needs revalidation.
+#[tracing::instrument]
+pub async fn get_archive_bytes(ar_txid: &str) -> bytes::Bytes {
+    let backend = cache_backend().await;
+    if let Some(backend) = &backend {
+        if let Ok(Some(entry)) = backend.get(&archive_key(ar_txid)).await {
+            return entry.value.into();
+        }
+    }
+
+    let max_compressed_bytes = DecodeLimits::from_env().max_compressed_bytes;
+    let data = download_archive(ar_txid, |res| async move {
+        read_capped(res, max_compressed_bytes)
+            .await
+            .ok_or_else(|| Error::msg("archive body failed or is too large"))
+    })
+    .await
+    .map_or_else(|_| bytes::Bytes::new(), |(_, data)| data);
+
+    if let Some(backend) = &backend {
+        if !data.is_empty() {
+            let _ = backend.put(&archive_key(ar_txid), &data).await;
         }
     }
+    data
+}
 
-    Err(String::from("Error retrieving calldata from Arweave"))
+/// Buffers a response body, giving up once it grows past `limit` bytes.
+async fn read_capped(mut res: reqwest::Response, limit: u64) -> Option<bytes::Bytes> {
+    let mut data = bytes::BytesMut::new();
+    while let Some(chunk) = res.chunk().await.ok()? {
+        if (data.len() + chunk.len()) as u64 > limit {
+            return None;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Some(data.freeze())
 }
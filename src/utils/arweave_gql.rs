@@ -3,7 +3,16 @@ use axum::Json;
 use reqwest::Client;
 use serde_json::{Value, json};
 use serde::{Serialize, Deserialize};
+use crate::utils::cache::{cache_archive_id, get_cached_archive_id};
+use crate::utils::cache_backend::{archive_id_key, cache_backend, mapping_ttl_secs};
 use crate::utils::constants::{ARWEAVE_GATEWAY_URL, WVM_EXEX_ADDRESS};
+use crate::utils::metrics::WVM_RPC;
+use crate::utils::provenance::{record_stage, CacheOutcome, Stage, StageTiming};
+use crate::utils::single_flight::{FlightError, SingleFlight};
+use crate::utils::telemetry::traced;
+use crate::utils::upstream::{call_upstream, http_client, json_rpc_result};
+use std::sync::LazyLock;
+use std::time::Instant;
 
 async fn send_graphql(gateway: &str, query: Value) -> Result<Value, Error> {
     let client = Client::new();
@@ -89,31 +98,82 @@ async fn send_graphql(gateway: &str, query: Value) -> Result<Value, Error> {
 // }
 
 
-#[derive(Deserialize, Debug, Clone, Default)]
-struct JsonRpcResponse {
-    result: String,
-    #[serde(skip)]
-    jsonrpc: String,
-    #[serde(skip)]
-    id: i32,
+#[tracing::instrument]
+pub async fn retrieve_block_from_arweave(block_id: u32) -> Result<String, Error> {
+    let started = Instant::now();
+    let hit = |arweave_id| {
+        record_stage(StageTiming::since(Stage::StorageProof, Some(CacheOutcome::Hit), started));
+        Ok(arweave_id)
+    };
+    if let Some(arweave_id) = get_cached_archive_id(block_id.into()) {
+        return hit(arweave_id);
+    }
+
+    let backend = cache_backend().await;
+    let key = archive_id_key(block_id.into());
+    let cached = match &backend {
+        Some(backend) => backend.get(&key).await.ok().flatten(),
+        None => None,
+    };
+    if let Some(entry) = cached.as_ref().filter(|e| e.is_fresh(mapping_ttl_secs())) {
+        let arweave_id = String::from_utf8_lossy(&entry.value).to_string();
+        cache_archive_id(block_id.into(), &arweave_id);
+        return hit(arweave_id);
+    }
+
+    let fetched = fetch_block_archive_id(block_id).await;
+    record_stage(StageTiming::since(Stage::StorageProof, Some(CacheOutcome::Miss), started));
+    match fetched {
+        Ok(arweave_id) => {
+            if !arweave_id.is_empty() {
+                cache_archive_id(block_id.into(), &arweave_id);
+                if let Some(backend) = &backend {
+                    let _ = backend.put(&key, arweave_id.as_bytes()).await;
+                }
+            }
+            Ok(arweave_id)
+        }
+        // the RPC is down, a stale mapping beats no mapping
+        Err(e) => match cached {
+            Some(entry) => Ok(String::from_utf8_lossy(&entry.value).to_string()),
+            None => Err(e),
+        },
+    }
 }
 
-pub async fn retrieve_block_from_arweave(block_id: u32) -> Result<String, Error> {
-    let client = reqwest::Client::new();
-    
-    let response: JsonRpcResponse = client
-        .post("https://testnet-rpc.wvm.dev")
-        .header("Content-Type", "application/json")
-        .json(&json!({
-            "jsonrpc": "2.0",
-            "method": "eth_getArweaveStorageProof",
-            "params": [block_id.to_string()],
-            "id": 1
-        }))
-        .send()
-        .await?
-        .json::<JsonRpcResponse>()
-        .await?;
+static STORAGE_PROOF_LOOKUPS: LazyLock<SingleFlight<u32, Result<String, FlightError>>> =
+    LazyLock::new(Default::default);
+
+async fn fetch_block_archive_id(block_id: u32) -> Result<String, Error> {
+    STORAGE_PROOF_LOOKUPS
+        .run(block_id, || async move {
+            fetch_storage_proof(block_id)
+                .await
+                .map_err(FlightError::from)
+        })
+        .await
+        .map_err(Error::from)
+}
 
-    Ok(response.result)
-}
\ No newline at end of file
+#[tracing::instrument]
+async fn fetch_storage_proof(block_id: u32) -> Result<String, Error> {
+    let client = http_client();
+
+    let request = || async {
+        let response = traced(client.post("https://testnet-rpc.wvm.dev"))
+            .header("Content-Type", "application/json")
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "method": "eth_getArweaveStorageProof",
+                "params": [block_id.to_string()],
+                "id": 1
+            }))
+            .send()
+            .await?;
+        json_rpc_result::<String>(response).await
+    };
+    // a block without a storage proof has no archive yet
+    let arweave_id = call_upstream(WVM_RPC, "eth_getArweaveStorageProof", request).await?;
+
+    Ok(arweave_id.unwrap_or_default())
+}
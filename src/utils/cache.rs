@@ -0,0 +1,169 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    ARCHIVE_ID_CACHE_CAPACITY, BLOCK_CACHE_MAX_BYTES, TX_HASH_CHECK_CACHE_CAPACITY,
+};
+use crate::utils::schemas::Block;
+use lru::LruCache;
+use reth_primitives::B256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counters {
+    fn record<T>(&self, lookup: Option<T>) -> Option<T> {
+        match lookup {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        lookup
+    }
+
+    fn stats(&self, entries: usize) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries as u64,
+        }
+    }
+}
+
+/// Decoded archived blocks, bounded by their approximate in-memory size.
+struct BlockCache {
+    entries: LruCache<String, (Arc<Block>, usize)>,
+    size: usize,
+    max_size: usize,
+}
+
+static BLOCKS: LazyLock<Mutex<BlockCache>> = LazyLock::new(|| {
+    Mutex::new(BlockCache {
+        entries: LruCache::unbounded(),
+        size: 0,
+        max_size: env_or("WVM_BLOCK_CACHE_MAX_BYTES", BLOCK_CACHE_MAX_BYTES),
+    })
+});
+static BLOCK_COUNTERS: LazyLock<Counters> = LazyLock::new(Default::default);
+
+/// WeaveVM block number -> Arweave id of its archive.
+static ARCHIVE_IDS: LazyLock<Mutex<LruCache<u64, String>>> = LazyLock::new(|| {
+    let capacity = env_or("WVM_ARCHIVE_ID_CACHE_CAPACITY", ARCHIVE_ID_CACHE_CAPACITY);
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+    ))
+});
+static ARCHIVE_ID_COUNTERS: LazyLock<Counters> = LazyLock::new(Default::default);
+
+/// (Arweave id, tx hash) -> whether the archived tx re-hashes to its id.
+/// Archives never change, so neither does the answer.
+type TxHashChecks = LruCache<(String, B256), Option<bool>>;
+
+static TX_HASH_CHECKS: LazyLock<Mutex<TxHashChecks>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(TX_HASH_CHECK_CACHE_CAPACITY).unwrap_or(NonZeroUsize::MIN),
+    ))
+});
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RetrieverCacheStats {
+    pub blocks: CacheStats,
+    pub archive_ids: CacheStats,
+}
+
+pub fn get_cached_block(arweave_id: &str) -> Option<Arc<Block>> {
+    let lookup = BLOCKS
+        .lock()
+        .unwrap()
+        .entries
+        .get(arweave_id)
+        .map(|(block, _)| block.clone());
+    BLOCK_COUNTERS.record(lookup)
+}
+
+pub fn get_cached_block_by_number(block_number: u64) -> Option<Arc<Block>> {
+    let arweave_id = ARCHIVE_IDS.lock().unwrap().peek(&block_number).cloned()?;
+    get_cached_block(&arweave_id)
+}
+
+pub fn cache_block(arweave_id: &str, block: Arc<Block>) {
+    if let Some(number) = block.number.as_deref().and_then(|n| n.parse().ok()) {
+        cache_archive_id(number, arweave_id);
+    }
+
+    let block_size = approximate_size(&block);
+    let mut cache = BLOCKS.lock().unwrap();
+    if block_size > cache.max_size {
+        return;
+    }
+    if let Some((_, replaced_size)) = cache
+        .entries
+        .put(arweave_id.to_string(), (block, block_size))
+    {
+        cache.size -= replaced_size;
+    }
+    cache.size += block_size;
+    while cache.size > cache.max_size {
+        match cache.entries.pop_lru() {
+            Some((_, (_, evicted_size))) => cache.size -= evicted_size,
+            None => break,
+        }
+    }
+}
+
+pub fn get_cached_archive_id(block_number: u64) -> Option<String> {
+    let lookup = ARCHIVE_IDS.lock().unwrap().get(&block_number).cloned();
+    ARCHIVE_ID_COUNTERS.record(lookup)
+}
+
+pub fn cache_archive_id(block_number: u64, arweave_id: &str) {
+    ARCHIVE_IDS
+        .lock()
+        .unwrap()
+        .put(block_number, arweave_id.to_string());
+}
+
+pub fn get_cached_tx_hash_check(arweave_id: &str, txid: B256) -> Option<Option<bool>> {
+    TX_HASH_CHECKS
+        .lock()
+        .unwrap()
+        .get(&(arweave_id.to_string(), txid))
+        .copied()
+}
+
+pub fn cache_tx_hash_check(arweave_id: &str, txid: B256, matches: Option<bool>) {
+    TX_HASH_CHECKS
+        .lock()
+        .unwrap()
+        .put((arweave_id.to_string(), txid), matches);
+}
+
+pub fn cache_stats() -> RetrieverCacheStats {
+    RetrieverCacheStats {
+        blocks: BLOCK_COUNTERS.stats(BLOCKS.lock().unwrap().entries.len()),
+        archive_ids: ARCHIVE_ID_COUNTERS.stats(ARCHIVE_IDS.lock().unwrap().len()),
+    }
+}
+
+/// Rough heap footprint of a decoded block, dominated by the calldata (shared
+/// between `transactions` and `transactions_and_calldata`).
+fn approximate_size(block: &Block) -> usize {
+    let txs: usize = block
+        .transactions
+        .iter()
+        .map(|tx| tx.input.len() + 256)
+        .sum();
+    txs + block.logs_bloom.as_ref().map_or(0, String::len) + 1024
+}
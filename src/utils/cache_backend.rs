@@ -0,0 +1,528 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    CACHE_ACCESS_FLUSH_BATCH, CACHE_DIR, CACHE_MAPPING_TTL_SECS, CACHE_MAX_BYTES, CACHE_RETRY_SECS,
+};
+use anyhow::Error;
+use axum::async_trait;
+use ethers::utils::{hex, keccak256};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, OnceCell};
+
+/// A cached value and when it was stored, in unix seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub value: Vec<u8>,
+    pub stored_at: u64,
+}
+
+impl CacheEntry {
+    pub fn is_fresh(&self, ttl_secs: Option<u64>) -> bool {
+        match ttl_secs {
+            Some(ttl) => now() < self.stored_at + ttl,
+            None => true,
+        }
+    }
+}
+
+/// Which entries go first once a backend is over its size budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// least recently read or written first
+    #[default]
+    Lru,
+    /// oldest write first
+    Fifo,
+    /// never evict, the budget is only advisory
+    NoEviction,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "fifo" => Ok(EvictionPolicy::Fifo),
+            "none" | "noeviction" => Ok(EvictionPolicy::NoEviction),
+            other => Err(Error::msg(format!("unknown eviction policy: {}", other))),
+        }
+    }
+}
+
+/// Persistent cache shared across restarts (and replicas, for Redis) for raw
+/// archive bytes and block -> archive mapping entries.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, Error>;
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Error>;
+    async fn remove(&self, key: &str) -> Result<(), Error>;
+}
+
+pub fn archive_key(arweave_id: &str) -> String {
+    format!("archive:{}", arweave_id)
+}
+
+pub fn archive_id_key(block_number: u64) -> String {
+    format!("archive-id:{}", block_number)
+}
+
+/// Archives are immutable, mapping entries are refreshed after this many seconds.
+pub fn mapping_ttl_secs() -> Option<u64> {
+    Some(env_or("WVM_CACHE_MAPPING_TTL_SECS", CACHE_MAPPING_TTL_SECS))
+}
+
+static BACKEND: OnceCell<Option<Arc<dyn CacheBackend>>> = OnceCell::const_new();
+
+/// When opening the backend last failed, in unix seconds, 0 if it never did.
+static BACKEND_FAILED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// The backend selected with `WVM_CACHE_BACKEND` (`disk`, `redis` or `none`).
+/// A backend that can't be opened is tried again after
+/// `WVM_CACHE_RETRY_SECS`, going without a cache in the meantime.
+pub async fn cache_backend() -> Option<Arc<dyn CacheBackend>> {
+    if let Some(backend) = BACKEND.get() {
+        return backend.clone();
+    }
+    let retry_secs = env_or("WVM_CACHE_RETRY_SECS", CACHE_RETRY_SECS);
+    let failed_at = BACKEND_FAILED_AT.load(Ordering::Relaxed);
+    if failed_at > 0 && now() < failed_at + retry_secs {
+        return None;
+    }
+    match BACKEND.get_or_try_init(open_backend).await {
+        Ok(backend) => backend.clone(),
+        Err(e) => {
+            tracing::warn!(error = %e, retry_secs, "could not open the cache backend");
+            BACKEND_FAILED_AT.store(now(), Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+async fn open_backend() -> Result<Option<Arc<dyn CacheBackend>>, Error> {
+    let backend: Arc<dyn CacheBackend> =
+        match env_or("WVM_CACHE_BACKEND", String::from("none")).as_str() {
+            "disk" => {
+                let dir = env_or("WVM_CACHE_DIR", CACHE_DIR.to_string());
+                let max_bytes = env_or("WVM_CACHE_MAX_BYTES", CACHE_MAX_BYTES);
+                let policy = env_or("WVM_CACHE_EVICTION", EvictionPolicy::default());
+                Arc::new(DiskCache::open(dir, max_bytes, policy).await?)
+            }
+            "redis" => {
+                let url = env_or("WVM_REDIS_URL", String::from("redis://127.0.0.1/"));
+                Arc::new(RedisCache::connect(&url).await?)
+            }
+            _ => return Ok(None),
+        };
+    Ok(Some(backend))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiskRef {
+    key: String,
+    digest: String,
+    stored_at: u64,
+    // nanos, so reads and writes within the same second still order
+    last_access: u64,
+    written_at: u64,
+}
+
+/// The refs of a disk cache and how many of them point at each blob, loaded
+/// once when the cache is opened.
+#[derive(Debug, Default)]
+struct DiskIndex {
+    refs: HashMap<String, DiskRef>,
+    blob_refs: HashMap<String, usize>,
+    /// Keys whose access time changed since their ref file was written.
+    accessed: HashSet<String>,
+}
+
+impl DiskIndex {
+    /// Points `disk_ref.key` at its blob, returning the digest of a blob no
+    /// ref points at anymore.
+    fn insert(&mut self, disk_ref: DiskRef) -> Option<String> {
+        *self.blob_refs.entry(disk_ref.digest.clone()).or_default() += 1;
+        self.accessed.remove(&disk_ref.key);
+        let previous = self.refs.insert(disk_ref.key.clone(), disk_ref)?;
+        self.release(previous.digest)
+    }
+
+    /// Drops the ref of `key`, returning the digest of a blob no ref points
+    /// at anymore.
+    fn remove(&mut self, key: &str) -> Option<String> {
+        self.accessed.remove(key);
+        let disk_ref = self.refs.remove(key)?;
+        self.release(disk_ref.digest)
+    }
+
+    fn release(&mut self, digest: String) -> Option<String> {
+        let count = self.blob_refs.get_mut(&digest)?;
+        *count -= 1;
+        if *count > 0 {
+            return None;
+        }
+        self.blob_refs.remove(&digest);
+        Some(digest)
+    }
+
+    /// Keys in the order the policy evicts them.
+    fn eviction_order(&self, policy: EvictionPolicy) -> Vec<String> {
+        let mut refs: Vec<&DiskRef> = self.refs.values().collect();
+        match policy {
+            EvictionPolicy::Lru => refs.sort_by_key(|disk_ref| disk_ref.last_access),
+            _ => refs.sort_by_key(|disk_ref| disk_ref.written_at),
+        }
+        refs.into_iter()
+            .map(|disk_ref| disk_ref.key.clone())
+            .collect()
+    }
+}
+
+/// Content-addressed on-disk cache: values live in `blobs/<keccak256>` so equal
+/// payloads are stored once, and `refs/<keccak256(key)>` points keys at them.
+/// Refs are indexed in memory; LRU access times are written back in batches
+/// of `WVM_CACHE_ACCESS_FLUSH_BATCH` reads.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    policy: EvictionPolicy,
+    size: AtomicU64,
+    index: StdMutex<DiskIndex>,
+    /// Serializes writing and removing blobs against the ref counts.
+    blobs: Mutex<()>,
+}
+
+impl DiskCache {
+    pub async fn open(
+        dir: impl Into<PathBuf>,
+        max_bytes: u64,
+        policy: EvictionPolicy,
+    ) -> Result<Self, Error> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(dir.join("blobs")).await?;
+        tokio::fs::create_dir_all(dir.join("refs")).await?;
+        let cache = DiskCache {
+            dir,
+            max_bytes,
+            policy,
+            size: AtomicU64::new(0),
+            index: Default::default(),
+            blobs: Mutex::new(()),
+        };
+        let size = cache.blobs_size().await?;
+        cache.size.store(size, Ordering::Relaxed);
+        let refs = cache.all_refs().await?;
+        let mut index = cache.index.lock().unwrap();
+        for disk_ref in refs {
+            index.insert(disk_ref);
+        }
+        drop(index);
+        Ok(cache)
+    }
+
+    fn ref_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join("refs")
+            .join(hex::encode(keccak256(key.as_bytes())))
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.dir.join("blobs").join(digest)
+    }
+
+    async fn write_ref(&self, disk_ref: &DiskRef) -> Result<(), Error> {
+        let path = self.ref_path(&disk_ref.key);
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(disk_ref)?).await?;
+        tokio::fs::rename(tmp, path).await?;
+        Ok(())
+    }
+
+    async fn all_refs(&self) -> Result<Vec<DiskRef>, Error> {
+        let mut refs = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.dir.join("refs")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Ok(raw) = tokio::fs::read(entry.path()).await {
+                if let Ok(disk_ref) = serde_json::from_slice(&raw) {
+                    refs.push(disk_ref);
+                }
+            }
+        }
+        Ok(refs)
+    }
+
+    async fn blobs_size(&self) -> Result<u64, Error> {
+        let mut size = 0;
+        let mut entries = tokio::fs::read_dir(self.dir.join("blobs")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            size += entry.metadata().await?.len();
+        }
+        Ok(size)
+    }
+
+    /// Deletes a blob no ref points at anymore, with `blobs` held.
+    async fn remove_blob(&self, digest: &str) -> Result<(), Error> {
+        let path = self.blob_path(digest);
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            tokio::fs::remove_file(path).await?;
+            self.size.fetch_sub(metadata.len(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Drops the ref of `key` and its blob once unreferenced, with `blobs`
+    /// held.
+    async fn remove_ref(&self, key: &str) -> Result<(), Error> {
+        let orphan = self.index.lock().unwrap().remove(key);
+        match tokio::fs::remove_file(self.ref_path(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        match orphan {
+            Some(digest) => self.remove_blob(&digest).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the access times of the refs read since the last flush.
+    async fn flush_access_times(&self) -> Result<(), Error> {
+        let accessed: Vec<DiskRef> = {
+            let mut index = self.index.lock().unwrap();
+            let keys: Vec<String> = index.accessed.drain().collect();
+            keys.iter()
+                .filter_map(|key| index.refs.get(key).cloned())
+                .collect()
+        };
+        for disk_ref in &accessed {
+            self.write_ref(disk_ref).await?;
+        }
+        Ok(())
+    }
+
+    async fn evict(&self) -> Result<(), Error> {
+        if self.policy == EvictionPolicy::NoEviction
+            || self.size.load(Ordering::Relaxed) <= self.max_bytes
+        {
+            return Ok(());
+        }
+        let _blobs = self.blobs.lock().await;
+        let order = self.index.lock().unwrap().eviction_order(self.policy);
+        for key in order {
+            if self.size.load(Ordering::Relaxed) <= self.max_bytes {
+                break;
+            }
+            self.remove_ref(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CacheBackend for DiskCache {
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, Error> {
+        let Some(disk_ref) = self.index.lock().unwrap().refs.get(key).cloned() else {
+            return Ok(None);
+        };
+        let value = match tokio::fs::read(self.blob_path(&disk_ref.digest)).await {
+            Ok(value) => value,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if self.policy == EvictionPolicy::Lru {
+            let flush = {
+                let mut index = self.index.lock().unwrap();
+                if let Some(indexed) = index.refs.get_mut(key) {
+                    indexed.last_access = now_nanos();
+                    index.accessed.insert(key.to_string());
+                }
+                index.accessed.len()
+                    >= env_or("WVM_CACHE_ACCESS_FLUSH_BATCH", CACHE_ACCESS_FLUSH_BATCH)
+            };
+            if flush {
+                self.flush_access_times().await?;
+            }
+        }
+        Ok(Some(CacheEntry {
+            value,
+            stored_at: disk_ref.stored_at,
+        }))
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        let digest = hex::encode(keccak256(value));
+        let blob_path = self.blob_path(&digest);
+        let blobs = self.blobs.lock().await;
+        if tokio::fs::metadata(&blob_path).await.is_err() {
+            let tmp = blob_path.with_extension("tmp");
+            tokio::fs::write(&tmp, value).await?;
+            tokio::fs::rename(tmp, blob_path).await?;
+            self.size.fetch_add(value.len() as u64, Ordering::Relaxed);
+        }
+
+        let written_at = now_nanos();
+        let disk_ref = DiskRef {
+            key: key.to_string(),
+            digest,
+            stored_at: now(),
+            last_access: written_at,
+            written_at,
+        };
+        self.write_ref(&disk_ref).await?;
+        let orphan = self.index.lock().unwrap().insert(disk_ref);
+        if let Some(orphan) = orphan {
+            self.remove_blob(&orphan).await?;
+        }
+        drop(blobs);
+
+        self.evict().await
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Error> {
+        let _blobs = self.blobs.lock().await;
+        self.remove_ref(key).await
+    }
+}
+
+/// Redis-backed cache shared by all replicas. Values are stored as the 8-byte
+/// big-endian `stored_at` followed by the payload, without expiry. Size-based
+/// eviction is left to the server, which has to run with an `allkeys-*`
+/// `maxmemory-policy` for the cache to stay bounded.
+pub struct RedisCache {
+    conn: ConnectionManager,
+}
+
+impl RedisCache {
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(RedisCache { conn })
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, Error> {
+        let raw: Option<Vec<u8>> = self.conn.clone().get(key).await?;
+        Ok(raw.filter(|raw| raw.len() >= 8).map(|raw| {
+            let (stored_at, value) = raw.split_at(8);
+            CacheEntry {
+                value: value.to_vec(),
+                stored_at: u64::from_be_bytes(stored_at.try_into().unwrap()),
+            }
+        }))
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        let mut raw = Vec::with_capacity(8 + value.len());
+        raw.extend_from_slice(&now().to_be_bytes());
+        raw.extend_from_slice(value);
+        let _: () = self.conn.clone().set(key, raw).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Error> {
+        let _: () = self.conn.clone().del(key).await?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(cache: &dyn CacheBackend) {
+        cache.put("archive:a", b"block bytes").await.unwrap();
+        cache.put("archive:b", b"block bytes").await.unwrap();
+        let entry = cache.get("archive:a").await.unwrap().unwrap();
+        assert_eq!(entry.value, b"block bytes");
+        assert!(entry.is_fresh(None));
+        assert!(!entry.is_fresh(Some(0)));
+
+        cache.remove("archive:a").await.unwrap();
+        assert_eq!(cache.get("archive:a").await.unwrap(), None);
+        // b shares a's blob, removing a must not take it away
+        assert_eq!(
+            cache.get("archive:b").await.unwrap().unwrap().value,
+            b"block bytes"
+        );
+        cache.remove("archive:b").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("wvm-cache-test-{}", now_nanos()));
+        let cache = DiskCache::open(&dir, 1024, EvictionPolicy::Lru)
+            .await
+            .unwrap();
+        exercise(&cache).await;
+
+        // over budget, the least recently used key goes first
+        cache.put("archive:old", &[1; 400]).await.unwrap();
+        cache.put("archive:new", &[2; 400]).await.unwrap();
+        cache.get("archive:old").await.unwrap();
+        cache.put("archive:newest", &[3; 400]).await.unwrap();
+        assert!(cache.get("archive:old").await.unwrap().is_some());
+        assert!(cache.get("archive:new").await.unwrap().is_none());
+        assert!(cache.get("archive:newest").await.unwrap().is_some());
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_reopens_with_its_refs() {
+        let dir = std::env::temp_dir().join(format!("wvm-cache-reopen-{}", now_nanos()));
+        let cache = DiskCache::open(&dir, 1024, EvictionPolicy::Lru)
+            .await
+            .unwrap();
+        cache.put("archive:a", b"shared").await.unwrap();
+        cache.put("archive:b", b"shared").await.unwrap();
+        cache.put("archive:c", b"own").await.unwrap();
+        cache.flush_access_times().await.unwrap();
+        drop(cache);
+
+        let cache = DiskCache::open(&dir, 1024, EvictionPolicy::Lru)
+            .await
+            .unwrap();
+        cache.remove("archive:a").await.unwrap();
+        assert_eq!(
+            cache.get("archive:b").await.unwrap().unwrap().value,
+            b"shared"
+        );
+        // overwriting the only ref of a blob drops the blob
+        cache.put("archive:c", b"replaced").await.unwrap();
+        let own = cache.blob_path(&hex::encode(keccak256(b"own")));
+        assert!(tokio::fs::metadata(own).await.is_err());
+        assert_eq!(cache.size.load(Ordering::Relaxed), 14);
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local redis, run with WVM_REDIS_URL=redis://127.0.0.1/"]
+    async fn test_redis_cache() {
+        let url = std::env::var("WVM_REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+        let cache = RedisCache::connect(&url).await.unwrap();
+        exercise(&cache).await;
+    }
+}
@@ -0,0 +1,39 @@
+use crate::utils::constants::{
+    ARCHIVE_DECODE_TIMEOUT_MS, ARCHIVE_DOWNLOAD_TIMEOUT_MS, STORAGE_PROOF_TIMEOUT_MS,
+    TX_LOOKUP_TIMEOUT_MS,
+};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Reads `key` from the environment, falling back to `default` when it is
+/// unset or can't be parsed.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Upper bounds for each stage of a calldata retrieval.
+#[derive(Debug, Clone, Copy)]
+pub struct StageTimeouts {
+    pub tx_lookup: Duration,
+    pub storage_proof: Duration,
+    pub archive_download: Duration,
+    pub archive_decode: Duration,
+}
+
+impl StageTimeouts {
+    pub fn from_env() -> Self {
+        let millis = |key, default| Duration::from_millis(env_or(key, default));
+        StageTimeouts {
+            tx_lookup: millis("WVM_TX_LOOKUP_TIMEOUT_MS", TX_LOOKUP_TIMEOUT_MS),
+            storage_proof: millis("WVM_STORAGE_PROOF_TIMEOUT_MS", STORAGE_PROOF_TIMEOUT_MS),
+            archive_download: millis(
+                "WVM_ARCHIVE_DOWNLOAD_TIMEOUT_MS",
+                ARCHIVE_DOWNLOAD_TIMEOUT_MS,
+            ),
+            archive_decode: millis("WVM_ARCHIVE_DECODE_TIMEOUT_MS", ARCHIVE_DECODE_TIMEOUT_MS),
+        }
+    }
+}
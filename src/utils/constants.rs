@@ -1,3 +1,51 @@
 pub const WVM_RPC_URL: &str = "https://testnet-rpc.wvm.dev";
 pub const ARWEAVE_GATEWAY_URL: &str = "https://arweave.net";
 pub const WVM_EXEX_ADDRESS: &str = "-hpyuoGQNcL2-cBpOSkHqmaiNtRklJM_6dcSe0QCbRs"; // equal to "5JUE58yemNynRDeQDyVECKbGVCQbnX7unPrBRqCPVn5Z"
+pub const INDEX_DB_PATH: &str = "wvm_index.db";
+pub const INDEX_SYNC_BATCH_SIZE: u64 = 100;
+pub const INDEX_SYNC_INTERVAL_SECS: u64 = 30;
+pub const INDEX_SYNC_MAX_ATTEMPTS: u32 = 5;
+pub const INDEX_SYNC_MAX_BACKOFF_SECS: u64 = 10 * 60;
+pub const BLOCK_CACHE_MAX_BYTES: usize = 256 * 1024 * 1024;
+pub const ARCHIVE_ID_CACHE_CAPACITY: usize = 100_000;
+pub const TX_HASH_CHECK_CACHE_CAPACITY: usize = 10_000;
+pub const CACHE_DIR: &str = "wvm_cache";
+pub const CACHE_MAX_BYTES: u64 = 10 * 1024 * 1024 * 1024;
+pub const CACHE_MAPPING_TTL_SECS: u64 = 24 * 60 * 60;
+pub const CACHE_RETRY_SECS: u64 = 30;
+pub const CACHE_ACCESS_FLUSH_BATCH: usize = 64;
+pub const FALLBACK_CACHE_TTL_SECS: u64 = 60;
+pub const TX_LOOKUP_TIMEOUT_MS: u64 = 10_000;
+pub const STORAGE_PROOF_TIMEOUT_MS: u64 = 10_000;
+pub const ARCHIVE_DOWNLOAD_TIMEOUT_MS: u64 = 30_000;
+pub const ARCHIVE_DECODE_TIMEOUT_MS: u64 = 10_000;
+pub const ARCHIVE_MAX_COMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+pub const ARCHIVE_MAX_DECOMPRESSED_BYTES: u64 = 512 * 1024 * 1024;
+pub const ARCHIVE_MAX_COMPRESSION_RATIO: u64 = 1000;
+pub const HEALTH_CHECK_TIMEOUT_MS: u64 = 5_000;
+pub const HEALTH_MAX_HEAD_AGE_SECS: u64 = 120;
+pub const HEALTH_CACHE_SECS: u64 = 5;
+pub const USAGE_DB_PATH: &str = "wvm_usage.db";
+pub const INCIDENTS_DB_PATH: &str = "wvm_incidents.db";
+pub const INCIDENTS_PAGE_SIZE: u64 = 100;
+pub const ANONYMOUS_RATE_PER_SEC: f64 = 5.0;
+pub const ANONYMOUS_BURST: f64 = 20.0;
+pub const ANONYMOUS_DAILY_QUOTA: u64 = 10_000;
+pub const API_KEY_RATE_PER_SEC: f64 = 50.0;
+pub const API_KEY_BURST: f64 = 200.0;
+pub const API_KEY_DAILY_QUOTA: u64 = 1_000_000;
+pub const TRUSTED_PROXIES: usize = 0;
+pub const BUCKET_SWEEP_INTERVAL_SECS: u64 = 60;
+pub const UPSTREAM_CONNECT_TIMEOUT_MS: u64 = 3_000;
+pub const UPSTREAM_READ_TIMEOUT_MS: u64 = 10_000;
+pub const UPSTREAM_MAX_RETRIES: u32 = 2;
+pub const UPSTREAM_RETRY_BASE_MS: u64 = 100;
+pub const UPSTREAM_RETRY_MAX_MS: u64 = 2_000;
+pub const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+pub const BREAKER_OPEN_SECS: u64 = 30;
+pub const UPSTREAM_MAX_CONCURRENCY: usize = 32;
+pub const UPSTREAM_MAX_QUEUE: usize = 64;
+pub const UPSTREAM_BUSY_RETRY_AFTER_SECS: u64 = 1;
+pub const HEDGE_PERCENTILE: f64 = 95.0;
+pub const HEDGE_MIN_DELAY_MS: u64 = 50;
+pub const HEDGE_MAX_RATE: f64 = 0.05;
@@ -0,0 +1,174 @@
+use anyhow::Error;
+use axum::http::header::ACCEPT;
+use axum::http::{HeaderMap, StatusCode};
+use borsh::BorshSerialize;
+use serde::{Deserialize, Serialize};
+
+/// Output formats of the calldata, war and block routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MsgPack,
+    Borsh,
+    /// The payload itself: calldata bytes, or the archive as stored on Arweave.
+    Raw,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FormatQuery {
+    pub format: Option<String>,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "cbor" => Some(Format::Cbor),
+            "msgpack" | "messagepack" => Some(Format::MsgPack),
+            "borsh" => Some(Format::Borsh),
+            "raw" => Some(Format::Raw),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MsgPack)
+            }
+            "application/x-borsh" => Some(Format::Borsh),
+            "application/octet-stream" => Some(Format::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MsgPack => "application/msgpack",
+            Format::Borsh => "application/x-borsh",
+            Format::Raw => "application/octet-stream",
+        }
+    }
+}
+
+/// Picks the response format: `?format=` wins, then the supported `Accept`
+/// entry with the highest q-value (header order breaks ties). Requests
+/// without either get JSON.
+pub fn negotiate(headers: &HeaderMap, query: &FormatQuery) -> Result<Format, (StatusCode, String)> {
+    if let Some(name) = &query.format {
+        return Format::from_name(name).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "unsupported format {}, use json, cbor, msgpack, borsh or raw",
+                    name
+                ),
+            )
+        });
+    }
+
+    let mut accepted: Vec<(f32, Option<Format>)> = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let media_type = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((q, Format::from_media_type(&media_type)))
+        })
+        .collect();
+    if accepted.is_empty() {
+        return Ok(Format::Json);
+    }
+    accepted.sort_by(|a, b| b.0.total_cmp(&a.0));
+    accepted
+        .into_iter()
+        .filter(|(q, _)| *q > 0.0)
+        .find_map(|(_, format)| format)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_ACCEPTABLE,
+                "supported: application/json, application/cbor, application/msgpack, application/x-borsh, application/octet-stream"
+                    .to_string(),
+            )
+        })
+}
+
+/// Encodes `value` in a structured format. Byte fields are hex strings in
+/// JSON and native byte strings in CBOR, MessagePack and Borsh.
+pub fn encode<T: Serialize + BorshSerialize>(value: &T, format: Format) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::Json => Ok(serde_json::to_vec(value)?),
+        Format::Cbor => {
+            let mut out = Vec::new();
+            ciborium::into_writer(value, &mut out).map_err(|e| Error::msg(format!("{:?}", e)))?;
+            Ok(out)
+        }
+        Format::MsgPack => Ok(rmp_serde::to_vec_named(value)?),
+        Format::Borsh => Ok(borsh::to_vec(value)?),
+        Format::Raw => Err(Error::msg("raw output has no structured encoding")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let none = FormatQuery::default();
+        assert_eq!(negotiate(&HeaderMap::new(), &none).unwrap(), Format::Json);
+        assert_eq!(
+            negotiate(&accept("application/cbor"), &none).unwrap(),
+            Format::Cbor
+        );
+        assert_eq!(
+            negotiate(
+                &accept("application/json;q=0.5, application/x-msgpack"),
+                &none
+            )
+            .unwrap(),
+            Format::MsgPack
+        );
+        assert_eq!(
+            negotiate(&accept("text/html, */*;q=0.1"), &none).unwrap(),
+            Format::Json
+        );
+        assert_eq!(
+            negotiate(&accept("text/html"), &none).unwrap_err().0,
+            StatusCode::NOT_ACCEPTABLE
+        );
+
+        let query = FormatQuery {
+            format: Some("borsh".to_string()),
+        };
+        assert_eq!(
+            negotiate(&accept("application/cbor"), &query).unwrap(),
+            Format::Borsh
+        );
+        let query = FormatQuery {
+            format: Some("xml".to_string()),
+        };
+        assert_eq!(
+            negotiate(&HeaderMap::new(), &query).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -1,106 +1,369 @@
-use crate::utils::arweave::get_tx_calldata_from_arweave;
-use crate::utils::arweave_gql::retrieve_block_from_arweave;
-use crate::utils::schemas::HandlerGetCalldata;
-use crate::utils::wvm_client::{decode_calldata_to_wvm_archiver, retrieve_wvm_block_ref_from_txid};
+use crate::utils::incidents::{record_incident, IncidentReport};
+use crate::utils::metrics::{observe_calldata_result, observe_payload};
+use crate::utils::pipeline::{retrieve, Retrieval, TxSelector};
+use crate::utils::policy::{CalldataDiff, PolicyError, SourcePolicy};
+use crate::utils::provenance::{record_stage, Stage, StageTiming};
+use crate::utils::schemas::{CalldataResponse, CalldataResponseV2};
+use crate::utils::wvm_client::try_decode_calldata_to_wvm_archiver;
+use anyhow::Error;
 use axum::response::Json;
+use ethers::types::U256;
+use reth_primitives::Bytes;
 use serde_json::Value;
+use std::time::Instant;
+use tracing::Span;
 
-pub async fn get_calldata(txid: String) -> Json<Value> {
-    let wvm_block_of_txid = retrieve_wvm_block_ref_from_txid(&txid).await;
-    let from_wvm_calldata_of_txid = wvm_block_of_txid.calldata;
-    let arweave_block_hash_of_txid =
-        retrieve_block_from_arweave(wvm_block_of_txid.number.as_u32()).await.unwrap_or_default();
-    let from_arweave_calldata_of_txid =
-        get_tx_calldata_from_arweave(arweave_block_hash_of_txid.as_str(), txid)
-            .await
-            .unwrap_or("0x".to_string());
-    let wvm_data_da = from_arweave_calldata_of_txid != "0x";
-    let ar_data_archive = from_arweave_calldata_of_txid != "0x";
-    let da_archive_is_equal_data =
-        from_arweave_calldata_of_txid == from_wvm_calldata_of_txid && wvm_data_da;
-    let calldata = handle_calldata(from_arweave_calldata_of_txid, from_wvm_calldata_of_txid);
-    let res_object = HandlerGetCalldata::new(
-        calldata,
-        arweave_block_hash_of_txid,
-        wvm_block_of_txid.hash,
-        wvm_block_of_txid.number.as_u32(),
-        Some(String::from("")),
-        wvm_data_da,
-        ar_data_archive,
-        da_archive_is_equal_data,
-    );
-    let res = serde_json::to_value(res_object).unwrap();
-    Json(res)
+/// `/v1` calldata JSON of `txid`. Fails with a `pipeline::RetrievalError`
+/// for a malformed or unknown txid, and when an upstream the retrieval needs
+/// is unavailable.
+pub async fn get_calldata(txid: String) -> Result<Json<Value>, Error> {
+    let res = resolve_calldata(TxSelector::TxId(txid), false, SourcePolicy::default()).await?;
+    Ok(Json(serde_json::to_value(res)?))
 }
 
-pub async fn get_war_calldata(txid: String) -> Json<Value> {
-    let wvm_block_of_txid = retrieve_wvm_block_ref_from_txid(&txid).await;
-    let from_wvm_calldata_of_txid = wvm_block_of_txid.calldata;
-    let arweave_block_hash_of_txid =
-        retrieve_block_from_arweave(wvm_block_of_txid.number.as_u32()).await.unwrap_or_default();
-    let from_arweave_calldata_of_txid =
-        get_tx_calldata_from_arweave(arweave_block_hash_of_txid.as_str(), txid)
-            .await
-            .unwrap_or("0x".to_string());
-    let wvm_data_da = from_arweave_calldata_of_txid != "0x";
-    let ar_data_archive = from_arweave_calldata_of_txid != "0x";
-    let da_archive_is_equal_data =
-        from_arweave_calldata_of_txid == from_wvm_calldata_of_txid && wvm_data_da;
-    let raw_war_calldata_struct =
-        decode_calldata_to_wvm_archiver(&from_arweave_calldata_of_txid).await;
-    let raw_war_calldata_json = serde_json::to_value(&raw_war_calldata_struct).unwrap();
-    let raw_war_calldata = serde_json::to_string(&raw_war_calldata_json).unwrap();
-    let calldata = handle_calldata(from_arweave_calldata_of_txid, from_wvm_calldata_of_txid);
-    let res_object = HandlerGetCalldata::new(
-        calldata,
-        arweave_block_hash_of_txid,
-        wvm_block_of_txid.hash,
-        wvm_block_of_txid.number.as_u32(),
-        raw_war_calldata.into(),
-        wvm_data_da,
-        ar_data_archive,
-        da_archive_is_equal_data,
+pub async fn get_war_calldata(txid: String) -> Result<Json<Value>, Error> {
+    let res = resolve_calldata(TxSelector::TxId(txid), true, SourcePolicy::default()).await?;
+    Ok(Json(serde_json::to_value(res)?))
+}
+
+pub async fn get_calldata_by_tag(tag: [String; 2]) -> Result<Json<Value>, Error> {
+    let res = resolve_calldata(TxSelector::Tag(tag), false, SourcePolicy::default()).await?;
+    Ok(Json(serde_json::to_value(res)?))
+}
+
+/// Typed counterpart of the getters above, for callers that serialize the
+/// response themselves. `war` also decodes the calldata as a war block,
+/// `policy` picks the source of the served calldata. Run it in
+/// `provenance::with_provenance` to also get the sources and stage timings.
+#[tracing::instrument]
+pub async fn resolve_calldata(
+    selector: TxSelector,
+    war: bool,
+    policy: SourcePolicy,
+) -> Result<CalldataResponse, Error> {
+    let retrieval = retrieve(selector).await?;
+    log_incident(&retrieval);
+    let da_available = retrieval.da_available;
+    let war_decoded_calldata = if war {
+        // calldata that isn't a (reasonably sized) war block is reported as null
+        let archived = retrieval.arweave_calldata.clone().unwrap_or_default();
+        match try_decode_calldata_to_wvm_archiver(&archived).await {
+            Ok(raw_war_calldata_struct) => {
+                let raw_war_calldata_json = serde_json::to_value(&raw_war_calldata_struct).unwrap();
+                serde_json::to_string(&raw_war_calldata_json).ok()
+            }
+            Err(_) => None,
+        }
+    } else {
+        Some(String::from(""))
+    };
+    let started = Instant::now();
+    let res = calldata_response(retrieval, war_decoded_calldata, policy);
+    record_stage(StageTiming::since(Stage::Compare, None, started));
+    let res = res?;
+    observe_payload("calldata", res.calldata.len());
+    // v1 reports a missing side as unequal, but nothing was compared then
+    let compared = da_available && res.ar_data_archive;
+    observe_calldata_result(
+        compared.then_some(res.da_archive_is_equal_data),
+        res.ar_data_archive,
     );
+    Ok(res)
+}
+
+/// `/v2` counterpart of `resolve_calldata`. `war` decodes the served
+/// calldata, archived or not, as a war block.
+#[tracing::instrument]
+pub async fn resolve_calldata_v2(
+    selector: TxSelector,
+    war: bool,
+    policy: SourcePolicy,
+) -> Result<CalldataResponseV2, Error> {
+    let retrieval = retrieve(selector).await?;
+    log_incident(&retrieval);
+    let started = Instant::now();
+    let res = calldata_response_v2(retrieval, policy);
+    record_stage(StageTiming::since(Stage::Compare, None, started));
+    let mut res = res?;
+    if war {
+        if let Some(calldata) = &res.calldata {
+            res.war = try_decode_calldata_to_wvm_archiver(calldata).await.ok();
+        }
+    }
+    observe_payload("calldata", res.calldata.as_ref().map_or(0, |c| c.len()));
+    observe_calldata_result(res.da_archive_is_equal_data, res.ar_data_archive);
+    Ok(res)
+}
 
-    let res = serde_json::to_value(res_object).unwrap();
+/// Records the discrepancy `retrieval` ran into in the incident log, off the
+/// executor and without waiting on it. A log that can't be written doesn't
+/// fail the request.
+fn log_incident(retrieval: &Retrieval) {
+    if let Some(report) = IncidentReport::from_retrieval(retrieval) {
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| {
+                if let Err(e) = record_incident(&report) {
+                    tracing::warn!(txid = %report.txid, error = %e, "could not record incident");
+                }
+            })
+        });
+    }
+}
+
+fn calldata_response_v2(
+    retrieval: Retrieval,
+    policy: SourcePolicy,
+) -> Result<CalldataResponseV2, PolicyError> {
+    let Retrieval {
+        txid,
+        block_ref,
+        arweave_id,
+        wvm_calldata,
+        da_available,
+        arweave_calldata,
+        mismatch,
+        ..
+    } = retrieval;
+    let arweave_calldata = arweave_calldata.filter(|calldata| !calldata.is_empty());
+    let wvm_calldata = da_available.then_some(wvm_calldata);
+    let da_archive_is_equal_data = match (&arweave_calldata, &wvm_calldata) {
+        (Some(archived), Some(served)) => Some(archived == served),
+        _ => None,
+    };
+    // the RPC reports pending and unknown txs at block 0, archive-only
+    // retrievals know their block from the index
+    let block_known = block_ref.hash.is_some() || !da_available;
+    Ok(CalldataResponseV2 {
+        txid,
+        ar_data_archive: arweave_calldata.is_some(),
+        calldata: select(
+            policy,
+            arweave_calldata.as_ref(),
+            wvm_calldata.as_ref(),
+            mismatch.as_ref(),
+        )?,
+        wvm_block_number: (block_known && block_ref.number <= U256::from(u64::MAX))
+            .then(|| block_ref.number.as_u64()),
+        wvm_block_hash: block_ref.hash,
+        arweave_id: Some(arweave_id).filter(|id| !id.is_empty()),
+        wvm_data_da: da_available,
+        da_archive_is_equal_data,
+        war: None,
+        mismatch,
+    })
+}
 
-    Json(res)
+/// `policy.select`, reporting the retrieval's full `mismatch` diagnostics
+/// (with the tx hash check) when the sources disagree.
+fn select(
+    policy: SourcePolicy,
+    archived: Option<&Bytes>,
+    served: Option<&Bytes>,
+    mismatch: Option<&CalldataDiff>,
+) -> Result<Option<Bytes>, PolicyError> {
+    policy
+        .select(archived, served)
+        .map_err(|e| match (e, mismatch) {
+            (PolicyError::Mismatch(_), Some(mismatch)) => PolicyError::Mismatch(mismatch.clone()),
+            (e, _) => e,
+        })
 }
 
-pub async fn get_calldata_by_tag(tag: [String; 2]) -> Json<Value> {
-    let (wvm_block_of_tag, txid) =
-        crate::utils::wvm_client::retrieve_wvm_block_ref_from_txtag(tag).await;
-    let from_wvm_calldata_of_txid = wvm_block_of_tag.calldata;
-    let arweave_block_hash_of_txid =
-        retrieve_block_from_arweave(wvm_block_of_tag.number.as_u32()).await.unwrap_or_default();
-    let from_arweave_calldata_of_txid =
-        get_tx_calldata_from_arweave(arweave_block_hash_of_txid.as_str(), txid)
-            .await
-            .unwrap_or("0x".to_string());
-    let wvm_data_da = from_arweave_calldata_of_txid != "0x";
-    let ar_data_archive = from_arweave_calldata_of_txid != "0x";
-    let da_archive_is_equal_data =
-        from_arweave_calldata_of_txid == from_wvm_calldata_of_txid && wvm_data_da;
-    let calldata = handle_calldata(from_arweave_calldata_of_txid, from_wvm_calldata_of_txid);
-    let res_object = HandlerGetCalldata::new(
+fn calldata_response(
+    retrieval: Retrieval,
+    war_decoded_calldata: Option<String>,
+    policy: SourcePolicy,
+) -> Result<CalldataResponse, PolicyError> {
+    let Retrieval {
+        block_ref,
+        arweave_id,
+        wvm_calldata,
+        da_available,
+        arweave_calldata,
+        mismatch,
+        ..
+    } = retrieval;
+    // an empty archived calldata is reported like a missing one
+    let arweave_calldata = arweave_calldata.filter(|calldata| !calldata.is_empty());
+    let wvm_data_da = arweave_calldata.is_some();
+    let ar_data_archive = arweave_calldata.is_some();
+    let da_archive_is_equal_data = arweave_calldata.as_ref() == Some(&wvm_calldata);
+    // v1 has always served the (empty) RPC calldata when neither source had any
+    let calldata = select(
+        policy,
+        arweave_calldata.as_ref(),
+        da_available.then_some(&wvm_calldata),
+        mismatch.as_ref(),
+    )?
+    .unwrap_or(wvm_calldata);
+    Ok(CalldataResponse {
         calldata,
-        arweave_block_hash_of_txid,
-        wvm_block_of_tag.hash,
-        wvm_block_of_tag.number.as_u32(),
-        Some(String::from("")),
+        arweave_block_hash: arweave_id,
+        wvm_block_hash: block_ref
+            .hash
+            .map_or(Bytes::new(), |hash| Bytes::copy_from_slice(hash.as_slice())),
+        wvm_block_id: block_ref.number.as_u32(),
+        war_decoded_calldata,
         wvm_data_da,
         ar_data_archive,
         da_archive_is_equal_data,
-    );
-    let res = serde_json::to_value(res_object).unwrap();
-    Json(res)
+    })
 }
 
-fn handle_calldata(ar_calldata: String, wvm_calldata: String) -> String {
-    if ar_calldata == "0x" {
-        // fallback to wvm calldata
-        wvm_calldata
-    } else {
-        ar_calldata
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pipeline::ArchiveState;
+    use crate::utils::schemas::GetBlockFromTx;
+    use ethers::types::U256;
+    use reth_primitives::B256;
+    use serde_json::json;
+
+    #[test]
+    fn test_calldata_response_keeps_v1_shape() {
+        let block_hash = B256::repeat_byte(0xab);
+        let retrieval = Retrieval {
+            txid: B256::repeat_byte(0x01),
+            block_ref: GetBlockFromTx::new(
+                U256::from(42),
+                Some(block_hash),
+                Bytes::from_static(&[0xde, 0xad]),
+            ),
+            arweave_id: "arweave-id".to_string(),
+            wvm_calldata: Bytes::from_static(&[0xde, 0xad]),
+            da_available: true,
+            archive: ArchiveState::Archived,
+            arweave_calldata: Some(Bytes::from_static(&[0xde, 0xad])),
+            mismatch: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(
+                calldata_response(
+                    retrieval.clone(),
+                    Some(String::new()),
+                    SourcePolicy::default()
+                )
+                .unwrap()
+            )
+            .unwrap(),
+            json!({
+                "calldata": "0xdead",
+                "arweave_block_hash": "arweave-id",
+                "wvm_block_hash": format!("{:#x}", block_hash),
+                "wvm_block_id": 42,
+                "war_decoded_calldata": "",
+                "wvm_data_da": true,
+                "ar_data_archive": true,
+                "da_archive_is_equal_data": true,
+            })
+        );
+
+        let missing = Retrieval {
+            arweave_calldata: None,
+            ..retrieval
+        };
+        let res = serde_json::to_value(
+            calldata_response(missing, None, SourcePolicy::default()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res["calldata"], "0xdead");
+        assert_eq!(res["ar_data_archive"], false);
+        assert_eq!(res["da_archive_is_equal_data"], false);
+        assert_eq!(res["war_decoded_calldata"], Value::Null);
+    }
+
+    #[test]
+    fn test_calldata_response_v2_separates_da_and_archive() {
+        let block_hash = B256::repeat_byte(0xab);
+        let retrieval = Retrieval {
+            txid: B256::repeat_byte(0x01),
+            block_ref: GetBlockFromTx::new(
+                U256::from(u64::MAX),
+                Some(block_hash),
+                Bytes::from_static(&[0xde, 0xad]),
+            ),
+            arweave_id: String::new(),
+            wvm_calldata: Bytes::from_static(&[0xde, 0xad]),
+            da_available: true,
+            archive: ArchiveState::Absent,
+            arweave_calldata: None,
+            mismatch: None,
+        };
+        assert_eq!(
+            serde_json::to_value(
+                calldata_response_v2(retrieval.clone(), SourcePolicy::default()).unwrap()
+            )
+            .unwrap(),
+            json!({
+                "txid": format!("{:#x}", B256::repeat_byte(0x01)),
+                "calldata": "0xdead",
+                "wvm_block_number": u64::MAX,
+                "wvm_block_hash": format!("{:#x}", block_hash),
+                "arweave_id": null,
+                "wvm_data_da": true,
+                "ar_data_archive": false,
+                "da_archive_is_equal_data": null,
+                "war": null,
+                "mismatch": null,
+            })
+        );
+
+        // archive-only: the RPC didn't serve the tx
+        let archive_only = Retrieval {
+            block_ref: GetBlockFromTx::new(U256::from(42), None, Bytes::new()),
+            arweave_id: "arweave-id".to_string(),
+            wvm_calldata: Bytes::new(),
+            da_available: false,
+            arweave_calldata: Some(Bytes::from_static(&[0xbe, 0xef])),
+            ..retrieval
+        };
+        let res = serde_json::to_value(
+            calldata_response_v2(archive_only.clone(), SourcePolicy::default()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res["calldata"], "0xbeef");
+        assert_eq!(res["wvm_block_number"], 42);
+        assert_eq!(res["wvm_block_hash"], Value::Null);
+        assert_eq!(res["wvm_data_da"], false);
+        assert_eq!(res["ar_data_archive"], true);
+        assert_eq!(res["da_archive_is_equal_data"], Value::Null);
+
+        // strict policies can't be satisfied without the RPC
+        for policy in [SourcePolicy::RpcOnly, SourcePolicy::RequireMatch] {
+            assert!(calldata_response_v2(archive_only.clone(), policy).is_err());
+        }
+        let res = calldata_response(archive_only, None, SourcePolicy::PreferRpc).unwrap();
+        assert_eq!(res.calldata, Bytes::from_static(&[0xbe, 0xef]));
+    }
+
+    #[test]
+    fn test_mismatch_is_reported() {
+        let archived = Bytes::from_static(b"0xdead");
+        let served = Bytes::from_static(&[0xde, 0xad]);
+        let mut mismatch = CalldataDiff::between(&archived, &served).unwrap();
+        mismatch.tx_hash_matches = Some(true);
+        let retrieval = Retrieval {
+            txid: B256::repeat_byte(0x01),
+            block_ref: GetBlockFromTx::new(U256::from(42), Some(B256::ZERO), served.clone()),
+            arweave_id: "arweave-id".to_string(),
+            wvm_calldata: served,
+            da_available: true,
+            archive: ArchiveState::Archived,
+            arweave_calldata: Some(archived),
+            mismatch: Some(mismatch.clone()),
+        };
+
+        let res = calldata_response_v2(retrieval.clone(), SourcePolicy::default()).unwrap();
+        assert_eq!(res.da_archive_is_equal_data, Some(false));
+        assert_eq!(res.mismatch.as_ref(), Some(&mismatch));
+        let json = serde_json::to_value(&res).unwrap();
+        assert_eq!(json["mismatch"]["kind"], "hex_encoding");
+        assert_eq!(json["mismatch"]["tx_hash_matches"], true);
+
+        // the 409 of require_match carries the same diagnostics
+        let err = calldata_response(retrieval, None, SourcePolicy::RequireMatch).unwrap_err();
+        assert_eq!(err, PolicyError::Mismatch(mismatch));
     }
 }
@@ -0,0 +1,243 @@
+use crate::utils::cache::cache_stats;
+use crate::utils::cache_backend::cache_backend;
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    ARWEAVE_GATEWAY_URL, HEALTH_CACHE_SECS, HEALTH_CHECK_TIMEOUT_MS, HEALTH_MAX_HEAD_AGE_SECS,
+    WVM_RPC_URL,
+};
+use crate::utils::index::{get_latest_block_number, index_state};
+use crate::utils::metrics::WVM_RPC;
+use crate::utils::telemetry::traced;
+use crate::utils::upstream::{call_upstream, http_client, json_rpc_result, JsonRpcCallError};
+use crate::utils::wvm_client::{retrieve_wvm_block_header, retrieve_wvm_head_block_number};
+use anyhow::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+/// JSON-RPC "method not found".
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CheckResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    /// What was observed, or why the check failed.
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+impl Readiness {
+    fn from_checks(checks: BTreeMap<&'static str, CheckResult>) -> Self {
+        Readiness {
+            ready: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+/// The last readiness report and when it was made.
+static LAST_READINESS: Mutex<Option<(Instant, Arc<Readiness>)>> = Mutex::const_new(None);
+
+/// The readiness of the instance, probed at most once per
+/// `WVM_HEALTH_CACHE_SECS`: callers meanwhile get the last report, and
+/// callers arriving during a probe wait for it, so polling the endpoint
+/// doesn't multiply the load on the upstreams.
+pub async fn readiness() -> Arc<Readiness> {
+    let ttl = Duration::from_secs(env_or("WVM_HEALTH_CACHE_SECS", HEALTH_CACHE_SECS));
+    let mut last = LAST_READINESS.lock().await;
+    if let Some((probed_at, report)) = last.as_ref() {
+        if probed_at.elapsed() < ttl {
+            return report.clone();
+        }
+    }
+    let report = Arc::new(probe().await);
+    *last = Some((Instant::now(), report.clone()));
+    report
+}
+
+/// Probes every dependency concurrently, each bounded by
+/// `WVM_HEALTH_CHECK_TIMEOUT_MS`. The instance is ready when all pass.
+async fn probe() -> Readiness {
+    let limit = Duration::from_millis(env_or(
+        "WVM_HEALTH_CHECK_TIMEOUT_MS",
+        HEALTH_CHECK_TIMEOUT_MS,
+    ));
+    let (wvm_rpc, arweave_gateway, storage_proof, cache, index) = tokio::join!(
+        run_check(limit, check_wvm_rpc()),
+        run_check(limit, check_arweave_gateway()),
+        run_check(limit, check_storage_proof()),
+        run_check(limit, check_cache()),
+        run_check(limit, check_index()),
+    );
+    Readiness::from_checks(BTreeMap::from([
+        ("wvm_rpc", wvm_rpc),
+        ("arweave_gateway", arweave_gateway),
+        ("storage_proof", storage_proof),
+        ("cache", cache),
+        ("index", index),
+    ]))
+}
+
+async fn run_check<F>(limit: Duration, check: F) -> CheckResult
+where
+    F: Future<Output = Result<String, Error>>,
+{
+    let start = Instant::now();
+    let (ok, detail) = match timeout(limit, check).await {
+        Ok(Ok(detail)) => (true, detail),
+        Ok(Err(e)) => (false, e.to_string()),
+        Err(_) => (false, format!("timed out after {}ms", limit.as_millis())),
+    };
+    CheckResult {
+        ok,
+        latency_ms: start.elapsed().as_millis() as u64,
+        detail,
+    }
+}
+
+/// The RPC answers and its head block is recent.
+async fn check_wvm_rpc() -> Result<String, Error> {
+    let head = retrieve_wvm_head_block_number().await?;
+    let header = retrieve_wvm_block_header(head)
+        .await?
+        .ok_or_else(|| Error::msg(format!("head block {} not found", head)))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let age = now.saturating_sub(header.timestamp.as_u64());
+    let max_age = env_or("WVM_HEALTH_MAX_HEAD_AGE_SECS", HEALTH_MAX_HEAD_AGE_SECS);
+    if age > max_age {
+        return Err(Error::msg(format!(
+            "head block {} is {}s old, max {}s",
+            head, age, max_age
+        )));
+    }
+    Ok(format!("head block {}, {}s old", head, age))
+}
+
+async fn check_arweave_gateway() -> Result<String, Error> {
+    let url = format!("{}/info", ARWEAVE_GATEWAY_URL);
+    let request = || async {
+        traced(http_client().get(&url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await
+    };
+    let info = call_upstream(ARWEAVE_GATEWAY_URL, "info", request).await?;
+    Ok(format!(
+        "{} reachable, height {}",
+        ARWEAVE_GATEWAY_URL, info["height"]
+    ))
+}
+
+/// The RPC knows `eth_getArweaveStorageProof`. Only "method not found" fails
+/// the check, a block that isn't archived yet doesn't.
+async fn check_storage_proof() -> Result<String, Error> {
+    let block_number = get_latest_block_number().await.unwrap_or(0);
+    let request = || async {
+        let response = traced(http_client().post(WVM_RPC_URL))
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "method": "eth_getArweaveStorageProof",
+                "params": [block_number.to_string()],
+                "id": 1
+            }))
+            .send()
+            .await?;
+        json_rpc_result::<String>(response).await
+    };
+    match call_upstream(WVM_RPC, "eth_getArweaveStorageProof", request).await {
+        Ok(Some(_)) => Ok(format!("supported, block {} archived", block_number)),
+        Ok(None) => Ok(format!(
+            "supported, block {} not archived yet",
+            block_number
+        )),
+        Err(e) => match e.downcast_ref::<JsonRpcCallError>() {
+            Some(JsonRpcCallError::Rpc {
+                code: METHOD_NOT_FOUND,
+                ..
+            }) => Err(Error::msg(
+                "eth_getArweaveStorageProof is not supported by the WeaveVM RPC",
+            )),
+            Some(JsonRpcCallError::Rpc { message, .. }) => {
+                Ok(format!("supported, block {}: {}", block_number, message))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// A configured persistent backend must be open and answering.
+async fn check_cache() -> Result<String, Error> {
+    let stats = cache_stats();
+    let in_memory = format!(
+        "{} blocks and {} archive ids in memory",
+        stats.blocks.entries, stats.archive_ids.entries
+    );
+    let configured = env_or("WVM_CACHE_BACKEND", String::from("none"));
+    match cache_backend().await {
+        Some(backend) => {
+            backend.get("health").await?;
+            Ok(format!("{} backend answering, {}", configured, in_memory))
+        }
+        None if configured == "none" => Ok(format!("no persistent backend, {}", in_memory)),
+        None => Err(Error::msg(format!(
+            "{} backend configured but unavailable",
+            configured
+        ))),
+    }
+}
+
+async fn check_index() -> Result<String, Error> {
+    match index_state().await? {
+        Some(synced) => Ok(format!("synced up to block {}", synced)),
+        None => Ok("not synced yet".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(ok: bool) -> CheckResult {
+        CheckResult {
+            ok,
+            latency_ms: 0,
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_ready_only_when_all_checks_pass() {
+        let ready =
+            Readiness::from_checks(BTreeMap::from([("a", check(true)), ("b", check(true))]));
+        assert!(ready.ready);
+
+        let unready =
+            Readiness::from_checks(BTreeMap::from([("a", check(true)), ("b", check(false))]));
+        assert!(!unready.ready);
+        assert_eq!(
+            serde_json::to_value(&unready).unwrap()["checks"]["b"]["ok"],
+            Value::Bool(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readiness_is_reused_within_ttl() {
+        let report = Arc::new(Readiness::from_checks(BTreeMap::from([("a", check(true))])));
+        *LAST_READINESS.lock().await = Some((Instant::now(), report.clone()));
+        assert!(Arc::ptr_eq(&readiness().await, &report));
+    }
+}
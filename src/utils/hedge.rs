@@ -0,0 +1,218 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{HEDGE_MAX_RATE, HEDGE_MIN_DELAY_MS, HEDGE_PERCENTILE};
+use crate::utils::metrics::observe_hedge;
+use anyhow::Error;
+use futures::future::{self, Either};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// Recent requests the latency percentile and hedge rate are computed over.
+const WINDOW: usize = 256;
+/// Primary latencies needed before the percentile is trusted.
+const MIN_SAMPLES: usize = 20;
+
+/// Hedging of archive downloads to a second Arweave gateway.
+#[derive(Debug, Clone)]
+pub struct HedgeConfig {
+    /// Second gateway, hedging is off while unset.
+    pub gateway: Option<String>,
+    /// Primary latency percentile after which the request is hedged.
+    pub percentile: f64,
+    pub min_delay: Duration,
+    /// Largest share of recent requests that may be hedged.
+    pub max_rate: f64,
+}
+
+impl HedgeConfig {
+    pub fn from_env() -> Self {
+        HedgeConfig {
+            gateway: std::env::var("WVM_HEDGE_GATEWAY_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            percentile: env_or("WVM_HEDGE_PERCENTILE", HEDGE_PERCENTILE).clamp(0.0, 100.0),
+            min_delay: Duration::from_millis(env_or("WVM_HEDGE_MIN_DELAY_MS", HEDGE_MIN_DELAY_MS)),
+            max_rate: env_or("WVM_HEDGE_MAX_RATE", HEDGE_MAX_RATE).clamp(0.0, 1.0),
+        }
+    }
+}
+
+static CONFIG: LazyLock<HedgeConfig> = LazyLock::new(HedgeConfig::from_env);
+
+pub fn hedge_config() -> &'static HedgeConfig {
+    &CONFIG
+}
+
+#[derive(Debug, Default)]
+struct HedgeState {
+    /// Time to a successful primary response.
+    latencies: VecDeque<Duration>,
+    /// Whether each recent request was hedged.
+    hedged: VecDeque<bool>,
+}
+
+impl HedgeState {
+    fn record_latency(&mut self, latency: Duration) {
+        self.latencies.push_back(latency);
+        if self.latencies.len() > WINDOW {
+            self.latencies.pop_front();
+        }
+    }
+
+    fn record_request(&mut self, hedged: bool) {
+        self.hedged.push_back(hedged);
+        if self.hedged.len() > WINDOW {
+            self.hedged.pop_front();
+        }
+    }
+
+    /// How long the primary gets before being hedged, `None` until there
+    /// are enough samples.
+    fn delay(&self, config: &HedgeConfig) -> Option<Duration> {
+        if self.latencies.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted = self.latencies.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let rank = (config.percentile / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank].max(config.min_delay))
+    }
+
+    /// Whether one more hedge keeps the recent hedge rate within the cap.
+    fn may_hedge(&self, config: &HedgeConfig) -> bool {
+        let hedges = self.hedged.iter().filter(|hedged| **hedged).count();
+        (hedges + 1) as f64 <= config.max_rate * (self.hedged.len() + 1) as f64
+    }
+}
+
+static STATE: LazyLock<Mutex<HedgeState>> = LazyLock::new(Default::default);
+
+/// Runs `primary`, and once it is slower than the configured percentile of
+/// recent primary latencies also `secondary`. The first successful response
+/// wins and the other request is cancelled by being dropped; when one fails,
+/// the other one is awaited.
+pub async fn hedged<T, P, S, SF>(primary: P, secondary: S) -> Result<T, Error>
+where
+    P: Future<Output = Result<T, Error>>,
+    S: FnOnce() -> SF,
+    SF: Future<Output = Result<T, Error>>,
+{
+    race(&STATE, hedge_config(), primary, secondary).await
+}
+
+async fn race<T, P, S, SF>(
+    state: &Mutex<HedgeState>,
+    config: &HedgeConfig,
+    primary: P,
+    secondary: S,
+) -> Result<T, Error>
+where
+    P: Future<Output = Result<T, Error>>,
+    S: FnOnce() -> SF,
+    SF: Future<Output = Result<T, Error>>,
+{
+    let start = Instant::now();
+    let mut primary = pin!(primary);
+    let delay = state.lock().unwrap().delay(config);
+    let early = match delay {
+        Some(delay) => timeout(delay, primary.as_mut()).await.ok(),
+        None => Some(primary.as_mut().await),
+    };
+    let hedge = early.is_none() && {
+        let mut state = state.lock().unwrap();
+        let hedge = state.may_hedge(config);
+        state.record_request(hedge);
+        hedge
+    };
+    if !hedge {
+        let res = match early {
+            Some(res) => {
+                state.lock().unwrap().record_request(false);
+                res
+            }
+            None => primary.await,
+        };
+        if res.is_ok() {
+            state.lock().unwrap().record_latency(start.elapsed());
+        }
+        return res;
+    }
+
+    let secondary = pin!(secondary());
+    let (winner, res) = match future::select(primary, secondary).await {
+        Either::Left((Ok(value), _)) => ("primary", Ok(value)),
+        Either::Right((Ok(value), _)) => ("hedge", Ok(value)),
+        Either::Left((Err(e), secondary)) => ("hedge", secondary.await.map_err(|_| e)),
+        Either::Right((Err(_), primary)) => ("primary", primary.await),
+    };
+    // a cancelled primary took at least this long, which keeps the
+    // percentile from drifting down while hedges win
+    state.lock().unwrap().record_latency(start.elapsed());
+    observe_hedge(winner);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_rate: f64) -> HedgeConfig {
+        HedgeConfig {
+            gateway: None,
+            percentile: 90.0,
+            min_delay: Duration::from_millis(5),
+            max_rate,
+        }
+    }
+
+    fn warmed_up(latency: Duration) -> Mutex<HedgeState> {
+        let mut state = HedgeState::default();
+        for _ in 0..MIN_SAMPLES {
+            state.record_latency(latency);
+            state.record_request(false);
+        }
+        Mutex::new(state)
+    }
+
+    #[test]
+    fn test_delay_and_rate_cap() {
+        let config = config(0.1);
+        let mut state = HedgeState::default();
+        for ms in 1..=MIN_SAMPLES as u64 - 1 {
+            state.record_latency(Duration::from_millis(ms * 10));
+        }
+        assert_eq!(state.delay(&config), None);
+        state.record_latency(Duration::from_millis(200));
+        assert_eq!(state.delay(&config), Some(Duration::from_millis(180)));
+
+        for _ in 0..9 {
+            state.record_request(false);
+        }
+        assert!(state.may_hedge(&config));
+        state.record_request(true);
+        assert!(!state.may_hedge(&config));
+    }
+
+    #[tokio::test]
+    async fn test_hedge_wins_over_slow_primary() {
+        let state = warmed_up(Duration::from_millis(5));
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok("primary")
+        };
+        let res = race(&state, &config(1.0), slow, || async { Ok("hedge") }).await;
+        assert_eq!(res.unwrap(), "hedge");
+
+        // with no hedge budget the primary is waited for
+        let state = warmed_up(Duration::from_millis(5));
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok("primary")
+        };
+        let res = race(&state, &config(0.0), slow, || async { Ok("hedge") }).await;
+        assert_eq!(res.unwrap(), "primary");
+    }
+}
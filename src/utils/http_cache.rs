@@ -0,0 +1,150 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::FALLBACK_CACHE_TTL_SECS;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ethers::utils::hex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Builds a JSON response with HTTP caching headers for a calldata lookup.
+///
+/// Results whose archive copy matches the DA calldata can never change, so
+/// they are marked `immutable`. Everything else (RPC-only fallbacks, tag
+/// lookups) only gets a short TTL. The strong `ETag` is derived from the
+/// resource id (tx hash or tag) and the digests of the calldata and body,
+/// and `If-None-Match` hits are answered with a 304.
+pub fn cached_json_response(
+    headers: &HeaderMap,
+    resource_id: &str,
+    body: Value,
+    allow_immutable: bool,
+) -> Response {
+    let calldata = body["calldata"].as_str().unwrap_or_default().to_string();
+    let archive_verified = body["da_archive_is_equal_data"].as_bool().unwrap_or(false);
+    let body = serde_json::to_vec(&body).unwrap();
+    cached_response(
+        headers,
+        resource_id,
+        body,
+        "application/json",
+        calldata.as_bytes(),
+        allow_immutable && archive_verified,
+    )
+}
+
+/// Same caching headers as `cached_json_response`, for an already encoded
+/// body of any content type. Bodies vary with the negotiated format, hence
+/// `Vary: Accept`.
+pub fn cached_response(
+    headers: &HeaderMap,
+    resource_id: &str,
+    body: Vec<u8>,
+    content_type: &'static str,
+    calldata: &[u8],
+    immutable: bool,
+) -> Response {
+    let body_digest = Sha256::digest(&body);
+    let etag = strong_etag(resource_id, calldata, &body_digest);
+    let cache_control = if immutable {
+        IMMUTABLE_CACHE_CONTROL.to_string()
+    } else {
+        format!(
+            "public, max-age={}",
+            env_or("WVM_FALLBACK_CACHE_TTL_SECS", FALLBACK_CACHE_TTL_SECS)
+        )
+    };
+
+    let mut res = if if_none_match(headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut res = body.into_response();
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res.headers_mut().insert(
+            "content-digest",
+            HeaderValue::from_str(&format!(
+                "sha-256=:{}:",
+                BASE64_STANDARD.encode(body_digest)
+            ))
+            .unwrap(),
+        );
+        res
+    };
+    res.headers_mut()
+        .insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    res.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&cache_control).unwrap(),
+    );
+    res.headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    res
+}
+
+fn strong_etag(resource_id: &str, calldata: &[u8], body_digest: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(resource_id.trim().to_lowercase().as_bytes());
+    hasher.update(Sha256::digest(calldata));
+    hasher.update(body_digest);
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// `If-None-Match` uses weak comparison (RFC 9110 13.1.2).
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn body(verified: bool) -> Value {
+        json!({ "calldata": "0xdead", "da_archive_is_equal_data": verified })
+    }
+
+    #[test]
+    fn test_cache_control_and_etag() {
+        let txid = "0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a";
+        let verified = cached_json_response(&HeaderMap::new(), txid, body(true), true);
+        let fallback = cached_json_response(&HeaderMap::new(), txid, body(false), true);
+
+        assert_eq!(verified.status(), StatusCode::OK);
+        assert_eq!(verified.headers()[CACHE_CONTROL], IMMUTABLE_CACHE_CONTROL);
+        assert!(fallback.headers()[CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .starts_with("public, max-age="));
+        assert_ne!(verified.headers()[ETAG], fallback.headers()[ETAG]);
+        assert!(verified.headers()["content-digest"]
+            .to_str()
+            .unwrap()
+            .starts_with("sha-256=:"));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let txid = "0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a";
+        let res = cached_json_response(&HeaderMap::new(), txid, body(true), true);
+        let etag = res.headers()[ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", {}", etag.to_str().unwrap())).unwrap(),
+        );
+        let res = cached_json_response(&headers, txid, body(true), true);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], etag);
+    }
+}
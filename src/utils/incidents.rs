@@ -0,0 +1,421 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{INCIDENTS_DB_PATH, INCIDENTS_PAGE_SIZE};
+use crate::utils::pipeline::{ArchiveState, Retrieval};
+use crate::utils::policy::CalldataDiff;
+use anyhow::Error;
+use reth_primitives::B256;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Discrepancies between the WeaveVM DA and the Arweave archives. Kept in
+/// SQLite so they can be followed up after the response that found them.
+static INCIDENTS: LazyLock<Mutex<Connection>> = LazyLock::new(|| {
+    let path = env_or("WVM_INCIDENTS_DB_PATH", INCIDENTS_DB_PATH.to_string());
+    let conn = Connection::open(&path).expect("could not open the incident log");
+    init(&conn).expect("could not initialize the incident log");
+    Mutex::new(conn)
+});
+
+fn init(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        CREATE TABLE IF NOT EXISTS incidents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            source TEXT NOT NULL,
+            txid TEXT NOT NULL,
+            block_number INTEGER,
+            arweave_id TEXT,
+            summary TEXT NOT NULL,
+            diff TEXT,
+            first_seen INTEGER NOT NULL,
+            last_seen INTEGER NOT NULL,
+            occurrences INTEGER NOT NULL,
+            acknowledged_at INTEGER,
+            ack_note TEXT
+        );
+        CREATE INDEX IF NOT EXISTS incidents_txid ON incidents (txid, kind);",
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    /// The archived calldata differs from the DA calldata.
+    Mismatch,
+    /// The tx is in a WeaveVM block but not in that block's archive.
+    MissingArchive,
+}
+
+/// What detected a discrepancy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentSource {
+    Request,
+    Audit,
+}
+
+fn name<T: Serialize>(value: T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn from_name<T: for<'de> Deserialize<'de>>(name: String) -> rusqlite::Result<T> {
+    serde_json::from_value(name.into()).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+/// A discrepancy to record.
+#[derive(Debug, Clone)]
+pub struct IncidentReport {
+    pub kind: IncidentKind,
+    pub source: IncidentSource,
+    pub txid: B256,
+    pub block_number: Option<u64>,
+    pub arweave_id: Option<String>,
+    pub diff: Option<CalldataDiff>,
+}
+
+impl IncidentReport {
+    /// The discrepancy a calldata retrieval ran into, if any. An archive is
+    /// only reported missing the tx once it was decoded without it: archives
+    /// lagging behind the chain or that couldn't be read aren't incidents.
+    pub fn from_retrieval(retrieval: &Retrieval) -> Option<IncidentReport> {
+        let kind = match &retrieval.mismatch {
+            Some(_) => IncidentKind::Mismatch,
+            None if retrieval.da_available
+                && retrieval.archive == ArchiveState::Absent
+                && retrieval.block_ref.hash.is_some() =>
+            {
+                IncidentKind::MissingArchive
+            }
+            None => return None,
+        };
+        Some(IncidentReport {
+            kind,
+            source: IncidentSource::Request,
+            txid: retrieval.txid,
+            block_number: Some(retrieval.block_ref.number.low_u64()),
+            arweave_id: Some(retrieval.arweave_id.clone()).filter(|id| !id.is_empty()),
+            diff: retrieval.mismatch.clone(),
+        })
+    }
+
+    fn summary(&self) -> String {
+        match (&self.diff, &self.arweave_id) {
+            (Some(diff), _) => format!(
+                "{} mismatch at byte {}: archive {} bytes, rpc {} bytes",
+                name(diff.kind),
+                diff.first_difference,
+                diff.archive_len,
+                diff.rpc_len
+            ),
+            (None, Some(arweave_id)) => format!("tx not found in archive {}", arweave_id),
+            (None, None) => "no archive found for the tx's block".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Incident {
+    pub id: u64,
+    pub kind: IncidentKind,
+    pub source: IncidentSource,
+    pub txid: String,
+    pub block_number: Option<u64>,
+    pub arweave_id: Option<String>,
+    pub summary: String,
+    pub diff: Option<CalldataDiff>,
+    /// Unix seconds.
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// Times the discrepancy was detected while the incident was open.
+    pub occurrences: u64,
+    /// Unix seconds, `null` while open.
+    pub acknowledged_at: Option<u64>,
+    pub ack_note: Option<String>,
+}
+
+impl Incident {
+    fn from_row(row: &Row) -> rusqlite::Result<Incident> {
+        let diff: Option<String> = row.get("diff")?;
+        Ok(Incident {
+            id: row.get("id")?,
+            kind: from_name(row.get("kind")?)?,
+            source: from_name(row.get("source")?)?,
+            txid: row.get("txid")?,
+            block_number: row.get("block_number")?,
+            arweave_id: row.get("arweave_id")?,
+            summary: row.get("summary")?,
+            diff: diff.and_then(|diff| serde_json::from_str(&diff).ok()),
+            first_seen: row.get("first_seen")?,
+            last_seen: row.get("last_seen")?,
+            occurrences: row.get("occurrences")?,
+            acknowledged_at: row.get("acknowledged_at")?,
+            ack_note: row.get("ack_note")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentStatus {
+    #[default]
+    Open,
+    Acknowledged,
+    All,
+}
+
+/// `GET /v1/incidents` filters. Incidents are listed newest first, `before`
+/// pages through them by id.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IncidentQuery {
+    #[serde(default)]
+    pub status: IncidentStatus,
+    pub txid: Option<String>,
+    pub kind: Option<IncidentKind>,
+    pub before: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Acknowledgement {
+    pub note: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Records a discrepancy. One that is already open for the same tx is
+/// counted on that incident instead of opening another.
+pub fn record_incident(report: &IncidentReport) -> Result<u64, Error> {
+    insert(&INCIDENTS.lock().unwrap(), report, now())
+}
+
+pub fn list_incidents(query: &IncidentQuery) -> Result<Vec<Incident>, Error> {
+    select(&INCIDENTS.lock().unwrap(), query)
+}
+
+/// Acknowledges an incident, `None` when there is none with that id.
+/// Acknowledging it again keeps the first acknowledgement.
+pub fn acknowledge_incident(id: u64, ack: &Acknowledgement) -> Result<Option<Incident>, Error> {
+    acknowledge(&INCIDENTS.lock().unwrap(), id, ack, now())
+}
+
+fn insert(conn: &Connection, report: &IncidentReport, now: u64) -> Result<u64, Error> {
+    let txid = format!("{:#x}", report.txid);
+    let kind = name(report.kind);
+    let diff = report
+        .diff
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let open = conn
+        .query_row(
+            "SELECT id FROM incidents
+            WHERE txid = ?1 AND kind = ?2 AND acknowledged_at IS NULL",
+            params![txid, kind],
+            |row| row.get::<_, u64>(0),
+        )
+        .optional()?;
+    if let Some(id) = open {
+        conn.execute(
+            "UPDATE incidents SET last_seen = ?2, occurrences = occurrences + 1,
+                block_number = ?3, arweave_id = ?4, summary = ?5, diff = ?6
+            WHERE id = ?1",
+            params![
+                id,
+                now,
+                report.block_number,
+                report.arweave_id,
+                report.summary(),
+                diff
+            ],
+        )?;
+        return Ok(id);
+    }
+    conn.execute(
+        "INSERT INTO incidents (kind, source, txid, block_number, arweave_id, summary, diff,
+            first_seen, last_seen, occurrences)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8, 1)",
+        params![
+            kind,
+            name(report.source),
+            txid,
+            report.block_number,
+            report.arweave_id,
+            report.summary(),
+            diff,
+            now
+        ],
+    )?;
+    Ok(conn.last_insert_rowid() as u64)
+}
+
+fn select(conn: &Connection, query: &IncidentQuery) -> Result<Vec<Incident>, Error> {
+    let acknowledged = match query.status {
+        IncidentStatus::Open => Some(false),
+        IncidentStatus::Acknowledged => Some(true),
+        IncidentStatus::All => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(INCIDENTS_PAGE_SIZE)
+        .clamp(1, INCIDENTS_PAGE_SIZE * 10);
+    let mut stmt = conn.prepare(
+        "SELECT * FROM incidents
+        WHERE (?1 IS NULL OR (acknowledged_at IS NOT NULL) = ?1)
+            AND (?2 IS NULL OR txid = ?2)
+            AND (?3 IS NULL OR kind = ?3)
+            AND (?4 IS NULL OR id < ?4)
+        ORDER BY id DESC
+        LIMIT ?5",
+    )?;
+    let rows = stmt.query_map(
+        params![
+            acknowledged,
+            query.txid.as_deref().map(str::to_lowercase),
+            query.kind.map(name),
+            query.before,
+            limit
+        ],
+        Incident::from_row,
+    )?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn acknowledge(
+    conn: &Connection,
+    id: u64,
+    ack: &Acknowledgement,
+    now: u64,
+) -> Result<Option<Incident>, Error> {
+    conn.execute(
+        "UPDATE incidents SET acknowledged_at = ?2, ack_note = ?3
+        WHERE id = ?1 AND acknowledged_at IS NULL",
+        params![id, now, ack.note],
+    )?;
+    let incident = conn
+        .query_row(
+            "SELECT * FROM incidents WHERE id = ?1",
+            params![id],
+            Incident::from_row,
+        )
+        .optional()?;
+    Ok(incident)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::schemas::GetBlockFromTx;
+    use ethers::types::U256;
+    use reth_primitives::Bytes;
+
+    fn retrieval(archived: Option<&'static [u8]>) -> Retrieval {
+        let served = Bytes::from_static(&[0xde, 0xad]);
+        let archived = archived.map(Bytes::from_static);
+        Retrieval {
+            txid: B256::repeat_byte(0x01),
+            block_ref: GetBlockFromTx::new(U256::from(42), Some(B256::ZERO), served.clone()),
+            arweave_id: "arweave-id".to_string(),
+            mismatch: archived
+                .as_ref()
+                .and_then(|archived| CalldataDiff::between(archived, &served)),
+            wvm_calldata: served,
+            da_available: true,
+            archive: match archived {
+                Some(_) => ArchiveState::Archived,
+                None => ArchiveState::Absent,
+            },
+            arweave_calldata: archived,
+        }
+    }
+
+    #[test]
+    fn test_report_from_retrieval() {
+        assert!(IncidentReport::from_retrieval(&retrieval(Some(&[0xde, 0xad]))).is_none());
+        let missing = IncidentReport::from_retrieval(&retrieval(None)).unwrap();
+        assert_eq!(missing.kind, IncidentKind::MissingArchive);
+        assert_eq!(missing.summary(), "tx not found in archive arweave-id");
+        let mismatch = IncidentReport::from_retrieval(&retrieval(Some(&[0xbe, 0xef]))).unwrap();
+        assert_eq!(mismatch.kind, IncidentKind::Mismatch);
+        assert_eq!(mismatch.block_number, Some(42));
+        assert!(mismatch.diff.is_some());
+
+        // pending txs aren't archived yet
+        let mut pending = retrieval(None);
+        pending.block_ref.hash = None;
+        assert!(IncidentReport::from_retrieval(&pending).is_none());
+        // neither are txs of blocks archiving hasn't caught up with
+        let mut lagging = retrieval(None);
+        lagging.archive = ArchiveState::NotYetArchived;
+        assert!(IncidentReport::from_retrieval(&lagging).is_none());
+        // an archive that timed out or failed to decode may still hold the tx
+        let mut unavailable = retrieval(None);
+        unavailable.archive = ArchiveState::Unavailable;
+        assert!(IncidentReport::from_retrieval(&unavailable).is_none());
+        // a tx archived without calldata is archived all the same
+        let mut empty = retrieval(None);
+        empty.archive = ArchiveState::Archived;
+        empty.arweave_calldata = Some(Bytes::new());
+        assert!(IncidentReport::from_retrieval(&empty).is_none());
+    }
+
+    #[test]
+    fn test_record_list_and_acknowledge() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        let mismatch = IncidentReport::from_retrieval(&retrieval(Some(&[0xbe, 0xef]))).unwrap();
+        let missing = IncidentReport::from_retrieval(&retrieval(None)).unwrap();
+
+        let id = insert(&conn, &mismatch, 100).unwrap();
+        assert_eq!(insert(&conn, &mismatch, 160).unwrap(), id);
+        insert(&conn, &missing, 200).unwrap();
+
+        let open = select(&conn, &IncidentQuery::default()).unwrap();
+        assert_eq!(open.len(), 2);
+        assert_eq!(open[0].kind, IncidentKind::MissingArchive);
+        assert_eq!((open[1].first_seen, open[1].last_seen), (100, 160));
+        assert_eq!(open[1].occurrences, 2);
+        assert_eq!(open[1].diff, mismatch.diff);
+
+        let query = IncidentQuery {
+            kind: Some(IncidentKind::Mismatch),
+            txid: Some(format!("{:#x}", B256::repeat_byte(0x01)).to_uppercase()),
+            ..Default::default()
+        };
+        assert_eq!(select(&conn, &query).unwrap().len(), 1);
+
+        let ack = Acknowledgement {
+            note: Some("archiver re-run".to_string()),
+        };
+        let acked = acknowledge(&conn, id, &ack, 300).unwrap().unwrap();
+        assert_eq!(acked.acknowledged_at, Some(300));
+        assert_eq!(acked.ack_note.as_deref(), Some("archiver re-run"));
+        let again = acknowledge(&conn, id, &Acknowledgement::default(), 400).unwrap();
+        assert_eq!(again.unwrap().acknowledged_at, Some(300));
+        assert_eq!(acknowledge(&conn, 999, &ack, 300).unwrap(), None);
+
+        assert_eq!(select(&conn, &IncidentQuery::default()).unwrap().len(), 1);
+        let acknowledged = IncidentQuery {
+            status: IncidentStatus::Acknowledged,
+            ..Default::default()
+        };
+        assert_eq!(select(&conn, &acknowledged).unwrap()[0].id, id);
+
+        // a recurrence after the acknowledgement opens a new incident
+        assert_ne!(insert(&conn, &mismatch, 500).unwrap(), id);
+    }
+}
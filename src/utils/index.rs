@@ -0,0 +1,454 @@
+use crate::utils::arweave::fetch_block_from_arweave;
+use crate::utils::arweave_gql::retrieve_block_from_arweave;
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    INDEX_DB_PATH, INDEX_SYNC_BATCH_SIZE, INDEX_SYNC_INTERVAL_SECS, INDEX_SYNC_MAX_ATTEMPTS,
+    INDEX_SYNC_MAX_BACKOFF_SECS,
+};
+use crate::utils::metrics::{observe_index_gap, set_index_sync_stalled};
+use crate::utils::schemas::Block;
+use crate::utils::upstream::is_upstream_rejection;
+use crate::utils::wvm_client::retrieve_wvm_head_block_number;
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tracing::Span;
+
+/// Where a WeaveVM transaction lives inside the archived chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_number: u64,
+    pub index: u64,
+    pub arweave_id: String,
+}
+
+/// Local SQLite index of tx hash -> (block number, index, Arweave id), filled by
+/// walking archived blocks so lookups keep working without the WeaveVM RPC.
+static INDEX: LazyLock<Mutex<Connection>> = LazyLock::new(|| {
+    // tests must not see, nor fill, the index of the host they run on
+    let conn = if cfg!(test) {
+        Connection::open_in_memory()
+    } else {
+        Connection::open(env_or("WVM_INDEX_PATH", INDEX_DB_PATH.to_string()))
+    }
+    .expect("could not open the retriever index");
+    init_schema(&conn).expect("could not initialize the retriever index");
+    Mutex::new(conn)
+});
+
+fn init_schema(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        CREATE TABLE IF NOT EXISTS archives (
+            block_number INTEGER PRIMARY KEY,
+            arweave_id TEXT NOT NULL,
+            block_hash TEXT
+        );
+        CREATE INDEX IF NOT EXISTS archives_block_hash ON archives (block_hash);
+        CREATE TABLE IF NOT EXISTS txs (
+            tx_hash TEXT PRIMARY KEY,
+            block_number INTEGER NOT NULL,
+            tx_index INTEGER NOT NULL,
+            arweave_id TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS gaps (
+            block_number INTEGER PRIMARY KEY,
+            reason TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sync_state (
+            key TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Runs `f` against the index on the blocking pool, so SQLite never holds up
+/// an async worker.
+async fn with_index<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    let span = Span::current();
+    let result =
+        tokio::task::spawn_blocking(move || span.in_scope(|| f(&mut INDEX.lock().unwrap())))
+            .await?;
+    Ok(result?)
+}
+
+pub async fn record_archive_id(block_number: u64, arweave_id: &str) -> Result<(), Error> {
+    let arweave_id = arweave_id.to_string();
+    with_index(move |conn| {
+        conn.execute(
+            "INSERT INTO archives (block_number, arweave_id) VALUES (?1, ?2)
+            ON CONFLICT (block_number) DO UPDATE SET arweave_id = excluded.arweave_id",
+            params![block_number, arweave_id],
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn record_block(block_number: u64, arweave_id: &str, block: &Block) -> Result<(), Error> {
+    let arweave_id = arweave_id.to_string();
+    let block_hash = block.hash.map(|hash| hash.to_string());
+    let tx_hashes: Vec<String> = block
+        .transactions
+        .iter()
+        .map(|tx| tx.hash.to_string())
+        .collect();
+    with_index(move |conn| {
+        let db_tx = conn.transaction()?;
+        db_tx.execute(
+            "INSERT OR REPLACE INTO archives (block_number, arweave_id, block_hash) VALUES (?1, ?2, ?3)",
+            params![block_number, arweave_id, block_hash],
+        )?;
+        for (i, tx_hash) in tx_hashes.iter().enumerate() {
+            db_tx.execute(
+                "INSERT OR REPLACE INTO txs (tx_hash, block_number, tx_index, arweave_id) VALUES (?1, ?2, ?3, ?4)",
+                params![tx_hash, block_number, i as u64, arweave_id],
+            )?;
+        }
+        db_tx.commit()
+    })
+    .await
+}
+
+pub async fn get_archive_id(block_number: u64) -> Option<String> {
+    with_index(move |conn| {
+        conn.query_row(
+            "SELECT arweave_id FROM archives WHERE block_number = ?1",
+            params![block_number],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn get_block_hash(block_number: u64) -> Option<String> {
+    with_index(move |conn| {
+        conn.query_row(
+            "SELECT block_hash FROM archives WHERE block_number = ?1",
+            params![block_number],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+    .ok()
+    .flatten()
+    .flatten()
+}
+
+pub async fn get_block_number(block_hash: &str) -> Option<u64> {
+    let block_hash = block_hash.trim().to_lowercase();
+    with_index(move |conn| {
+        conn.query_row(
+            "SELECT block_number FROM archives WHERE block_hash = ?1",
+            params![block_hash],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn get_tx_location(txid: &str) -> Option<TxLocation> {
+    let txid = txid.trim().to_lowercase();
+    with_index(move |conn| {
+        conn.query_row(
+            "SELECT block_number, tx_index, arweave_id FROM txs WHERE tx_hash = ?1",
+            params![txid],
+            |row| {
+                Ok(TxLocation {
+                    block_number: row.get(0)?,
+                    index: row.get(1)?,
+                    arweave_id: row.get(2)?,
+                })
+            },
+        )
+        .optional()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn get_latest_block_number() -> Option<u64> {
+    with_index(|conn| {
+        conn.query_row("SELECT MAX(block_number) FROM archives", [], |row| {
+            row.get(0)
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Last block the background sync has walked, if any.
+pub async fn get_synced_block_number() -> Option<u64> {
+    index_state().await.ok().flatten()
+}
+
+/// Sync cursor of the index, failing when the index can't be queried.
+pub async fn index_state() -> Result<Option<u64>, Error> {
+    with_index(|conn| {
+        conn.query_row(
+            "SELECT value FROM sync_state WHERE key = 'synced_block'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+}
+
+async fn set_synced_block_number(block_number: u64) -> Result<(), Error> {
+    with_index(move |conn| {
+        conn.execute(
+            "INSERT OR REPLACE INTO sync_state (key, value) VALUES ('synced_block', ?1)",
+            params![block_number],
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+/// Blocks the sync gave up on and skipped, with why.
+pub async fn get_gaps() -> Result<Vec<(u64, String)>, Error> {
+    with_index(|conn| {
+        let mut stmt =
+            conn.prepare("SELECT block_number, reason FROM gaps ORDER BY block_number")?;
+        let gaps = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(gaps)
+    })
+    .await
+}
+
+async fn record_gap(block_number: u64, reason: &str) -> Result<(), Error> {
+    let reason = reason.to_string();
+    with_index(move |conn| {
+        conn.execute(
+            "INSERT OR REPLACE INTO gaps (block_number, reason) VALUES (?1, ?2)",
+            params![block_number, reason],
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+/// Why the sync couldn't index a block.
+#[derive(Debug)]
+enum SyncFailure {
+    /// The storage proof answered that no archive is known for the block.
+    NotArchived,
+    /// The storage proof or the archive couldn't be fetched, decoded or
+    /// indexed, which says nothing about whether the block is archived.
+    Unavailable(Error),
+}
+
+impl fmt::Display for SyncFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncFailure::NotArchived => write!(f, "not archived"),
+            SyncFailure::Unavailable(e) => write!(f, "archive unavailable: {}", e),
+        }
+    }
+}
+
+/// Block the sync is stuck on and how many times in a row it failed there.
+static SYNC_FAILURES: Mutex<Option<(u64, u32)>> = Mutex::new(None);
+
+/// Counts a failed attempt at `block_number`, returning the attempts in a row.
+fn note_failure(block_number: u64) -> u32 {
+    let mut failures = SYNC_FAILURES.lock().unwrap();
+    let attempts = match *failures {
+        Some((stuck_at, attempts)) if stuck_at == block_number => attempts + 1,
+        _ => 1,
+    };
+    *failures = Some((block_number, attempts));
+    set_index_sync_stalled(attempts);
+    attempts
+}
+
+fn clear_failures() {
+    *SYNC_FAILURES.lock().unwrap() = None;
+    set_index_sync_stalled(0);
+}
+
+async fn index_block(block_number: u64) -> Result<(), SyncFailure> {
+    let arweave_id = retrieve_block_from_arweave(
+        u32::try_from(block_number).map_err(|e| SyncFailure::Unavailable(e.into()))?,
+    )
+    .await
+    .map_err(SyncFailure::Unavailable)?;
+    if arweave_id.is_empty() {
+        return Err(SyncFailure::NotArchived);
+    }
+    let block = fetch_block_from_arweave(&arweave_id)
+        .await
+        .map_err(SyncFailure::Unavailable)?;
+    record_block(block_number, &arweave_id, &block)
+        .await
+        .map_err(SyncFailure::Unavailable)
+}
+
+/// A block is a gap, rather than archiving lag, only once the storage proof
+/// says it isn't archived while a later block is.
+async fn is_confirmed_gap(block_number: u64, failure: &SyncFailure) -> bool {
+    match failure {
+        SyncFailure::Unavailable(_) => false,
+        SyncFailure::NotArchived => match u32::try_from(block_number + 1) {
+            Ok(next) => retrieve_block_from_arweave(next)
+                .await
+                .is_ok_and(|arweave_id| !arweave_id.is_empty()),
+            Err(_) => false,
+        },
+    }
+}
+
+/// Walks the next batch of archived blocks after the sync cursor and indexes
+/// them. Returns the number of blocks walked.
+///
+/// An empty index starts at `WVM_INDEX_START_BLOCK`, or at the current head
+/// when it isn't set: backfilling the whole chain is opt-in.
+///
+/// A block that fails stops the batch with an error, keeping the blocks
+/// walked before it, so it is retried on the next run. After
+/// `WVM_INDEX_SYNC_MAX_ATTEMPTS` failures in a row it is recorded as a gap
+/// and skipped, but only when the storage proof says it isn't archived while
+/// a later block is. A block that isn't archived yet is waited for, and one
+/// whose proof or archive can't be fetched is retried until it can.
+pub async fn sync_index_batch() -> Result<u64, Error> {
+    let head = retrieve_wvm_head_block_number().await?;
+    let start = match get_synced_block_number().await {
+        Some(synced) => synced + 1,
+        None => env_or("WVM_INDEX_START_BLOCK", head),
+    };
+    let end = head.min(start + env_or("WVM_INDEX_SYNC_BATCH_SIZE", INDEX_SYNC_BATCH_SIZE) - 1);
+    let max_attempts = env_or("WVM_INDEX_SYNC_MAX_ATTEMPTS", INDEX_SYNC_MAX_ATTEMPTS);
+
+    let mut walked = 0;
+    for block_number in start..=end {
+        if let Err(failure) = index_block(block_number).await {
+            // an unavailable upstream says nothing about the block
+            if let SyncFailure::Unavailable(e) = &failure {
+                if is_upstream_rejection(e) {
+                    return Err(Error::msg(failure.to_string()));
+                }
+            }
+            let attempts = note_failure(block_number);
+            if attempts < max_attempts || !is_confirmed_gap(block_number, &failure).await {
+                tracing::warn!(
+                    block = block_number,
+                    attempts,
+                    reason = %failure,
+                    "index sync stalled"
+                );
+                return Err(Error::msg(format!(
+                    "index sync stalled at block {}: {}",
+                    block_number, failure
+                )));
+            }
+            tracing::error!(
+                block = block_number,
+                attempts,
+                reason = %failure,
+                "index sync skipped a block"
+            );
+            record_gap(block_number, &failure.to_string()).await?;
+            observe_index_gap();
+        }
+        clear_failures();
+        set_synced_block_number(block_number).await?;
+        walked += 1;
+    }
+
+    Ok(walked)
+}
+
+/// Keeps the index in sync with newly archived blocks, forever. Backs off
+/// exponentially, up to `WVM_INDEX_SYNC_MAX_BACKOFF_SECS`, while it fails.
+pub async fn run_index_sync() {
+    let interval = env_or("WVM_INDEX_SYNC_INTERVAL_SECS", INDEX_SYNC_INTERVAL_SECS);
+    let max_backoff = env_or(
+        "WVM_INDEX_SYNC_MAX_BACKOFF_SECS",
+        INDEX_SYNC_MAX_BACKOFF_SECS,
+    );
+    let mut failures = 0;
+    loop {
+        // a full batch means we are behind, so go again without waiting
+        let batch_size = env_or("WVM_INDEX_SYNC_BATCH_SIZE", INDEX_SYNC_BATCH_SIZE);
+        let pause = match sync_index_batch().await {
+            Ok(walked) if walked == batch_size => {
+                failures = 0;
+                continue;
+            }
+            Ok(_) => {
+                failures = 0;
+                interval
+            }
+            Err(_) => {
+                failures += 1;
+                backoff_secs(interval, failures, max_backoff)
+            }
+        };
+        tokio::time::sleep(Duration::from_secs(pause)).await;
+    }
+}
+
+/// `interval` doubled for every failure past the first, capped at `max`.
+fn backoff_secs(interval: u64, failures: u32, max: u64) -> u64 {
+    interval
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(max.max(interval))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_failure_counts_per_block() {
+        assert_eq!(note_failure(7), 1);
+        assert_eq!(note_failure(7), 2);
+        assert_eq!(note_failure(8), 1);
+        clear_failures();
+        assert_eq!(note_failure(8), 1);
+        clear_failures();
+    }
+
+    #[test]
+    fn test_backoff_secs() {
+        assert_eq!(backoff_secs(30, 1, 600), 30);
+        assert_eq!(backoff_secs(30, 3, 600), 120);
+        assert_eq!(backoff_secs(30, 10, 600), 600);
+        assert_eq!(backoff_secs(30, u32::MAX, 600), 600);
+        // a cap below the interval never shortens it
+        assert_eq!(backoff_secs(30, 2, 10), 30);
+    }
+
+    #[tokio::test]
+    async fn test_gaps_are_recorded() {
+        record_gap(42, "not archived").await.unwrap();
+        assert!(get_gaps()
+            .await
+            .unwrap()
+            .contains(&(42, "not archived".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_archive_is_never_a_gap() {
+        let failure = SyncFailure::Unavailable(Error::msg("error sending request"));
+        assert!(!is_confirmed_gap(7, &failure).await);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    ARCHIVE_MAX_COMPRESSED_BYTES, ARCHIVE_MAX_COMPRESSION_RATIO, ARCHIVE_MAX_DECOMPRESSED_BYTES,
+};
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Below this much output the compression ratio isn't checked, brotli headers
+/// alone can make tiny inputs look extreme.
+const RATIO_CHECK_FLOOR: u64 = 1024 * 1024;
+
+/// Bounds applied while decompressing archives and war calldata.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_compressed_bytes: u64,
+    pub max_decompressed_bytes: u64,
+    pub max_compression_ratio: u64,
+}
+
+impl DecodeLimits {
+    pub fn from_env() -> Self {
+        DecodeLimits {
+            max_compressed_bytes: env_or(
+                "WVM_ARCHIVE_MAX_COMPRESSED_BYTES",
+                ARCHIVE_MAX_COMPRESSED_BYTES,
+            ),
+            max_decompressed_bytes: env_or(
+                "WVM_ARCHIVE_MAX_DECOMPRESSED_BYTES",
+                ARCHIVE_MAX_DECOMPRESSED_BYTES,
+            ),
+            max_compression_ratio: env_or(
+                "WVM_ARCHIVE_MAX_COMPRESSION_RATIO",
+                ARCHIVE_MAX_COMPRESSION_RATIO,
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    CompressedTooLarge { limit: u64 },
+    DecompressedTooLarge { limit: u64 },
+    CompressionRatioTooHigh { limit: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::CompressedTooLarge { limit } => {
+                write!(f, "compressed archive exceeds {} bytes", limit)
+            }
+            LimitError::DecompressedTooLarge { limit } => {
+                write!(f, "decompressed archive exceeds {} bytes", limit)
+            }
+            LimitError::CompressionRatioTooHigh { limit } => {
+                write!(f, "archive compression ratio exceeds {}:1", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl LimitError {
+    /// The limit that aborted a read, if `err` came from a limited reader.
+    pub fn from_io(err: &std::io::Error) -> Option<LimitError> {
+        err.get_ref()?.downcast_ref::<LimitError>().copied()
+    }
+
+    /// Same as `from_io`, for errors that went through `anyhow`.
+    pub fn find(err: &anyhow::Error) -> Option<LimitError> {
+        match err.downcast_ref::<LimitError>() {
+            Some(limit) => Some(*limit),
+            None => err.downcast_ref::<std::io::Error>().and_then(Self::from_io),
+        }
+    }
+}
+
+/// Brotli-decompresses `input` as it is read, failing with a `LimitError` as
+/// soon as the compressed size, decompressed size or ratio crosses `limits`.
+pub fn limited_brotli_reader<R: Read>(input: R, limits: DecodeLimits) -> impl Read {
+    let compressed = Arc::new(AtomicU64::new(0));
+    let input = CompressedSide {
+        inner: input,
+        read: compressed.clone(),
+        limit: limits.max_compressed_bytes,
+    };
+    DecompressedSide {
+        inner: brotli::Decompressor::new(input, 4096), // 4096 is the buffer size
+        compressed,
+        decompressed: 0,
+        limits,
+    }
+}
+
+struct CompressedSide<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl<R: Read> Read for CompressedSide<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let read = self.read.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        if read > self.limit {
+            return Err(std::io::Error::other(LimitError::CompressedTooLarge {
+                limit: self.limit,
+            }));
+        }
+        Ok(n)
+    }
+}
+
+struct DecompressedSide<R> {
+    inner: R,
+    compressed: Arc<AtomicU64>,
+    decompressed: u64,
+    limits: DecodeLimits,
+}
+
+impl<R: Read> Read for DecompressedSide<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.decompressed += n as u64;
+        if self.decompressed > self.limits.max_decompressed_bytes {
+            return Err(std::io::Error::other(LimitError::DecompressedTooLarge {
+                limit: self.limits.max_decompressed_bytes,
+            }));
+        }
+        let compressed = self.compressed.load(Ordering::Relaxed).max(1);
+        if self.decompressed > RATIO_CHECK_FLOOR
+            && self.decompressed / compressed > self.limits.max_compression_ratio
+        {
+            return Err(std::io::Error::other(LimitError::CompressionRatioTooHigh {
+                limit: self.limits.max_compression_ratio,
+            }));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+            writer.write_all(data).unwrap();
+        }
+        out
+    }
+
+    fn decompress(input: &[u8], limits: DecodeLimits) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        limited_brotli_reader(input, limits).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    const GENEROUS: DecodeLimits = DecodeLimits {
+        max_compressed_bytes: u64::MAX,
+        max_decompressed_bytes: u64::MAX,
+        max_compression_ratio: u64::MAX,
+    };
+
+    #[test]
+    fn test_limits() {
+        let bomb = compress(&vec![0u8; 8 * 1024 * 1024]);
+        assert_eq!(decompress(&bomb, GENEROUS).unwrap().len(), 8 * 1024 * 1024);
+
+        let limits = DecodeLimits {
+            max_decompressed_bytes: 1024 * 1024,
+            ..GENEROUS
+        };
+        let err = decompress(&bomb, limits).unwrap_err();
+        assert_eq!(
+            LimitError::from_io(&err),
+            Some(LimitError::DecompressedTooLarge { limit: 1024 * 1024 })
+        );
+
+        let limits = DecodeLimits {
+            max_compression_ratio: 100,
+            ..GENEROUS
+        };
+        let err = decompress(&bomb, limits).unwrap_err();
+        assert_eq!(
+            LimitError::from_io(&err),
+            Some(LimitError::CompressionRatioTooHigh { limit: 100 })
+        );
+
+        let limits = DecodeLimits {
+            max_compressed_bytes: 8,
+            ..GENEROUS
+        };
+        let err = decompress(&bomb, limits).unwrap_err();
+        assert_eq!(
+            LimitError::from_io(&err),
+            Some(LimitError::CompressedTooLarge { limit: 8 })
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use crate::utils::cache::{cache_stats, CacheStats};
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    exponential_buckets, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+/// Upstream label of the WeaveVM JSON-RPC, operations are the RPC methods.
+pub const WVM_RPC: &str = "wvm_rpc";
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<C: prometheus::core::Collector + Clone + 'static>(collector: C) -> C {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(IntCounterVec::new(Opts::new(name, help), labels).unwrap())
+}
+
+fn histogram_vec(name: &str, help: &str, labels: &[&str], buckets: Vec<f64>) -> HistogramVec {
+    register(HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels).unwrap())
+}
+
+/// 5ms .. ~40s
+fn latency_buckets() -> Vec<f64> {
+    exponential_buckets(0.005, 2.0, 14).unwrap()
+}
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "wvm_http_requests_total",
+        "HTTP requests served, by route and status",
+        &["route", "method", "status"],
+    )
+});
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    histogram_vec(
+        "wvm_http_request_duration_seconds",
+        "HTTP request latency, by route",
+        &["route", "method"],
+        latency_buckets(),
+    )
+});
+static UPSTREAM_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    histogram_vec(
+        "wvm_upstream_request_duration_seconds",
+        "Latency of WeaveVM RPC and Arweave gateway requests",
+        &["upstream", "operation"],
+        latency_buckets(),
+    )
+});
+static UPSTREAM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "wvm_upstream_errors_total",
+        "Failed WeaveVM RPC and Arweave gateway requests",
+        &["upstream", "operation"],
+    )
+});
+static DECODE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    histogram_vec(
+        "wvm_decode_duration_seconds",
+        "Time spent decoding archives and war calldata",
+        &["kind"],
+        latency_buckets(),
+    )
+});
+static PAYLOAD_BYTES: LazyLock<HistogramVec> = LazyLock::new(|| {
+    histogram_vec(
+        "wvm_payload_bytes",
+        "Size of archives and calldata handled by the retriever",
+        &["kind"],
+        // 64B .. 1GiB
+        exponential_buckets(64.0, 4.0, 13).unwrap(),
+    )
+});
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "wvm_cache_lookups_total",
+        "In-memory cache lookups, by cache and result",
+        &["cache", "result"],
+    )
+});
+static CACHE_HIT_RATIO: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "wvm_cache_hit_ratio",
+                "Share of in-memory cache lookups that hit, since start",
+            ),
+            &["cache"],
+        )
+        .unwrap(),
+    )
+});
+static CIRCUIT_OPEN: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "wvm_upstream_circuit_open",
+                "1 while the circuit breaker of an upstream is open",
+            ),
+            &["upstream"],
+        )
+        .unwrap(),
+    )
+});
+static UPSTREAM_QUEUED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "wvm_upstream_queued",
+                "Upstream calls waiting for a concurrency slot",
+            ),
+            &["upstream"],
+        )
+        .unwrap(),
+    )
+});
+static UPSTREAM_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "wvm_upstream_rejected_total",
+        "Upstream calls rejected because the upstream's queue was full",
+        &["upstream"],
+    )
+});
+static HEDGED_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "wvm_hedged_requests_total",
+        "Hedged archive downloads, by which gateway answered first",
+        &["winner"],
+    )
+});
+static DA_ARCHIVE_UNEQUAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "wvm_da_archive_unequal_total",
+            "Calldata results whose archived and DA calldata were compared and differ",
+        )
+        .unwrap(),
+    )
+});
+static ARCHIVE_MISSING: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "wvm_archive_missing_total",
+            "Calldata results without the tx in an Arweave archive",
+        )
+        .unwrap(),
+    )
+});
+static INDEX_SYNC_STALLED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "wvm_index_sync_stalled_attempts",
+            "Consecutive failed attempts of the index sync at its cursor, 0 while it progresses",
+        )
+        .unwrap(),
+    )
+});
+static INDEX_GAPS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "wvm_index_gaps_total",
+            "Blocks the index sync skipped after failing to index them",
+        )
+        .unwrap(),
+    )
+});
+
+/// Axum middleware counting and timing requests per matched route.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    HTTP_DURATION
+        .with_label_values(&[&route, &method])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&route, &method, res.status().as_str()])
+        .inc();
+    res
+}
+
+/// Times an upstream request and counts it as an error when it fails.
+pub async fn observe_upstream<T, E, F>(upstream: &str, operation: &str, request: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let res = request.await;
+    UPSTREAM_DURATION
+        .with_label_values(&[upstream, operation])
+        .observe(start.elapsed().as_secs_f64());
+    if res.is_err() {
+        UPSTREAM_ERRORS
+            .with_label_values(&[upstream, operation])
+            .inc();
+    }
+    res
+}
+
+/// `kind` is `archive` or `war`.
+pub fn observe_decode(kind: &str, started: Instant) {
+    DECODE_DURATION
+        .with_label_values(&[kind])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// `kind` is `archive` (compressed, as downloaded), `calldata` or `war`.
+pub fn observe_payload(kind: &str, bytes: usize) {
+    PAYLOAD_BYTES
+        .with_label_values(&[kind])
+        .observe(bytes as f64);
+}
+
+pub fn set_circuit_open(upstream: &str, open: bool) {
+    CIRCUIT_OPEN
+        .with_label_values(&[upstream])
+        .set(i64::from(open));
+}
+
+pub fn set_upstream_queued(upstream: &str, queued: usize) {
+    UPSTREAM_QUEUED
+        .with_label_values(&[upstream])
+        .set(queued as i64);
+}
+
+pub fn observe_upstream_rejected(upstream: &str) {
+    UPSTREAM_REJECTED.with_label_values(&[upstream]).inc();
+}
+
+/// `winner` is `primary` or `hedge`.
+pub fn observe_hedge(winner: &str) {
+    HEDGED_REQUESTS.with_label_values(&[winner]).inc();
+}
+
+/// `da_archive_is_equal_data` is `None` when a side was missing and nothing
+/// was compared, which counts as neither equal nor unequal.
+pub fn observe_calldata_result(da_archive_is_equal_data: Option<bool>, ar_data_archive: bool) {
+    if da_archive_is_equal_data == Some(false) {
+        DA_ARCHIVE_UNEQUAL.inc();
+    }
+    if !ar_data_archive {
+        ARCHIVE_MISSING.inc();
+    }
+}
+
+pub fn set_index_sync_stalled(attempts: u32) {
+    INDEX_SYNC_STALLED.set(i64::from(attempts));
+}
+
+pub fn observe_index_gap() {
+    INDEX_GAPS.inc();
+}
+
+fn sync_cache_stats(cache: &str, stats: &CacheStats) {
+    for (result, total) in [("hit", stats.hits), ("miss", stats.misses)] {
+        let counter = CACHE_LOOKUPS.with_label_values(&[cache, result]);
+        counter.inc_by(total.saturating_sub(counter.get()));
+    }
+    let lookups = stats.hits + stats.misses;
+    if lookups > 0 {
+        CACHE_HIT_RATIO
+            .with_label_values(&[cache])
+            .set(stats.hits as f64 / lookups as f64);
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    LazyLock::force(&DA_ARCHIVE_UNEQUAL);
+    LazyLock::force(&ARCHIVE_MISSING);
+    LazyLock::force(&INDEX_SYNC_STALLED);
+    LazyLock::force(&INDEX_GAPS);
+    let stats = cache_stats();
+    sync_cache_stats("blocks", &stats.blocks);
+    sync_cache_stats("archive_ids", &stats.archive_ids);
+    TextEncoder::new()
+        .encode_to_string(&REGISTRY.gather())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let _ = observe_upstream(WVM_RPC, "eth_blockNumber", async { Err::<(), _>("down") }).await;
+        observe_calldata_result(Some(false), false);
+        observe_payload("calldata", 100);
+
+        let text = render();
+        assert!(text.contains(
+            "wvm_upstream_errors_total{operation=\"eth_blockNumber\",upstream=\"wvm_rpc\"} 1"
+        ));
+        assert!(text.contains("wvm_archive_missing_total 1"));
+        assert!(text.contains("wvm_payload_bytes_count{kind=\"calldata\"} 1"));
+        assert!(text.contains("wvm_cache_lookups_total{cache=\"blocks\""));
+    }
+}
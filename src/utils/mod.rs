@@ -1,7 +1,28 @@
+pub mod access;
+pub mod archive_rpc;
 pub mod arweave;
 pub mod arweave_gql;
+pub mod cache;
+pub mod cache_backend;
+pub mod config;
 pub mod constants;
+pub mod formats;
 pub mod getters;
+pub mod graphql;
+pub mod health;
+pub mod hedge;
+pub mod http_cache;
+pub mod incidents;
+pub mod index;
+pub mod limits;
+pub mod metrics;
+pub mod openapi;
+pub mod pipeline;
+pub mod policy;
+pub mod provenance;
 pub mod schemas;
 pub mod server_handlers;
+pub mod single_flight;
+pub mod telemetry;
+pub mod upstream;
 pub mod wvm_client;
@@ -0,0 +1,674 @@
+use crate::utils::access::UsageRecord;
+use crate::utils::cache::RetrieverCacheStats;
+use crate::utils::health::Readiness;
+use crate::utils::incidents::Incident;
+use crate::utils::policy::CalldataDiff;
+use crate::utils::provenance::Provenance;
+use crate::utils::schemas::{Block, CalldataResponseV2, HandlerGetCalldata, WeaveVMArchiverBlock};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use std::sync::LazyLock;
+
+static DOCUMENT: LazyLock<Value> = LazyLock::new(build);
+
+/// OpenAPI 3 document of every route in `main.rs`, with the response schemas
+/// generated from the Rust types.
+pub fn document() -> &'static Value {
+    &DOCUMENT
+}
+
+/// Redoc page rendering `/openapi.json`.
+pub fn redoc_page() -> &'static str {
+    r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>wvm-data-retriever API</title>
+    <meta charset="utf-8"/>
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+  </head>
+  <body>
+    <redoc spec-url="/openapi.json"></redoc>
+    <script src="https://cdn.redoc.ly/redoc/latest/bundles/redoc.standalone.js"></script>
+  </body>
+</html>"#
+}
+
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(generator.subschema_for::<T>()).unwrap()
+}
+
+fn path_param(name: &str, description: &str, example: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": { "type": "string" },
+        "example": example
+    })
+}
+
+fn text(description: &str, example: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" }, "example": example } }
+    })
+}
+
+fn json_body(description: &str, schema: Value, example: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema, "example": example } }
+    })
+}
+
+fn error(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/responses/{}", name) })
+}
+
+/// `200` of a negotiated route: `json_schema` as JSON, the binary formats
+/// as opaque bytes.
+fn negotiated(description: &str, json_schema: Value, example: Value) -> Value {
+    let binary = json!({ "schema": { "type": "string", "format": "binary" } });
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": json_schema, "example": example },
+            "application/cbor": binary,
+            "application/msgpack": binary,
+            "application/x-borsh": binary,
+            "application/octet-stream": binary
+        }
+    })
+}
+
+/// Responses every rate limited API route may answer with.
+fn with_access_errors(mut responses: Value) -> Value {
+    responses["401"] = error("Unauthorized");
+    responses["429"] = error("TooManyRequests");
+    responses
+}
+
+fn calldata_operation(
+    schema_name: &str,
+    summary: &str,
+    parameters: Vec<Value>,
+    example: Value,
+) -> Value {
+    let mut parameters = parameters;
+    parameters.push(json!({ "$ref": "#/components/parameters/format" }));
+    parameters.push(json!({ "$ref": "#/components/parameters/policy" }));
+    parameters.push(json!({ "$ref": "#/components/parameters/debug" }));
+    parameters.push(json!({ "$ref": "#/components/parameters/debugHeader" }));
+    json!({
+        "get": {
+            "tags": ["calldata"],
+            "summary": summary,
+            "parameters": parameters,
+            "responses": with_access_errors(json!({
+                "200": negotiated(
+                    "Calldata with its DA and archive verification",
+                    json!({ "$ref": format!("#/components/schemas/{}", schema_name) }),
+                    example,
+                ),
+                "304": { "description": "`If-None-Match` matched the `ETag`" },
+                "400": error("BadRequest"),
+                "404": error("NotFound"),
+                "406": error("NotAcceptable"),
+                "409": error("PolicyConflict"),
+                "500": error("RetrievalFailed"),
+                "503": error("UpstreamRejected")
+            }))
+        }
+    })
+}
+
+fn calldata_example(war_decoded_calldata: Value) -> Value {
+    json!({
+        "calldata": "0x1b0b04f8c48c6a3a",
+        "arweave_block_hash": "yzbW3oLZ1Bm6BxkQPXXzEOYwpIOO3Nwxn7uvwFsPmNE",
+        "wvm_block_hash": "0x7ec5b1f1ec4e8b0e85f8c8ae1bb0b8a4c0e4e57bdbcd7fbb0b3b5d1a5c0f1b2e",
+        "wvm_block_id": 1827400,
+        "war_decoded_calldata": war_decoded_calldata,
+        "wvm_data_da": true,
+        "ar_data_archive": true,
+        "da_archive_is_equal_data": true
+    })
+}
+
+fn calldata_example_v2(war: Value) -> Value {
+    json!({
+        "txid": "0x9f6ae1b4a3d2c0e3bd2c7e5bcd7d2a6f4f4ea0b5a2f3bcd5a9b7f0a1c2d3e4f5",
+        "calldata": "0x1b0b04f8c48c6a3a",
+        "wvm_block_number": 1827400,
+        "wvm_block_hash": "0x7ec5b1f1ec4e8b0e85f8c8ae1bb0b8a4c0e4e57bdbcd7fbb0b3b5d1a5c0f1b2e",
+        "arweave_id": "yzbW3oLZ1Bm6BxkQPXXzEOYwpIOO3Nwxn7uvwFsPmNE",
+        "wvm_data_da": true,
+        "ar_data_archive": true,
+        "da_archive_is_equal_data": true,
+        "war": war,
+        "mismatch": null
+    })
+}
+
+fn incident_example(acknowledged_at: Value, ack_note: Value) -> Value {
+    json!({
+        "id": 17,
+        "kind": "mismatch",
+        "source": "request",
+        "txid": "0x3d2e5d0c4a5e3d3a3c7e1f5c0e4b6a5d2b8e1f0c9a7d6e5f4c3b2a1908f7e6d5",
+        "block_number": 2191540,
+        "arweave_id": "3ZsQG1bEzqCMRIQm3XFVe3oTNiRvfg0ADTgcWnxpO5c",
+        "summary": "content mismatch at byte 4: archive 68 bytes, rpc 68 bytes",
+        "diff": {
+            "archive_len": 68,
+            "rpc_len": 68,
+            "first_difference": 4,
+            "archive_keccak256": "0x3f1c...",
+            "rpc_keccak256": "0x8a0e...",
+            "kind": "content",
+            "tx_hash_matches": true
+        },
+        "first_seen": 1792368000,
+        "last_seen": 1792371600,
+        "occurrences": 3,
+        "acknowledged_at": acknowledged_at,
+        "ack_note": ack_note
+    })
+}
+
+fn build() -> Value {
+    let mut generator = SchemaGenerator::new(SchemaSettings::openapi3());
+    schema::<HandlerGetCalldata>(&mut generator);
+    schema::<CalldataResponseV2>(&mut generator);
+    schema::<CalldataDiff>(&mut generator);
+    // added to calldata bodies by `?debug=true`
+    schema::<Provenance>(&mut generator);
+    // the content of `war_decoded_calldata`
+    schema::<WeaveVMArchiverBlock>(&mut generator);
+    let block = schema::<Block>(&mut generator);
+    let cache_stats = schema::<RetrieverCacheStats>(&mut generator);
+    let readiness = schema::<Readiness>(&mut generator);
+    let usage = schema::<Vec<UsageRecord>>(&mut generator);
+    let incidents = schema::<Vec<Incident>>(&mut generator);
+    let incident = schema::<Incident>(&mut generator);
+    let mut schemas = generator
+        .take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap()))
+        .collect::<Map<_, _>>();
+    schemas["HandlerGetCalldata"]["example"] = calldata_example(json!(""));
+    schemas["CalldataResponseV2"]["example"] = calldata_example_v2(Value::Null);
+    // request bodies handled by async-graphql and the JSON-RPC dispatcher
+    schemas.insert(
+        "GraphQLRequest".to_string(),
+        json!({
+            "type": "object",
+            "required": ["query"],
+            "properties": {
+                "query": { "type": "string" },
+                "operationName": { "type": "string", "nullable": true },
+                "variables": { "type": "object", "nullable": true }
+            }
+        }),
+    );
+    schemas.insert(
+        "JsonRpcRequest".to_string(),
+        json!({
+            "type": "object",
+            "required": ["jsonrpc", "method"],
+            "properties": {
+                "jsonrpc": { "type": "string", "enum": ["2.0"] },
+                "id": {},
+                "method": { "type": "string" },
+                "params": { "type": "array", "items": {} }
+            }
+        }),
+    );
+
+    let txid = path_param(
+        "txid",
+        "WeaveVM transaction hash",
+        "0x9f6ae1b4a3d2c0e3bd2c7e5bcd7d2a6f4f4ea0b5a2f3bcd5a9b7f0a1c2d3e4f5",
+    );
+    let readiness_example = json!({
+        "ready": true,
+        "checks": {
+            "wvm_rpc": { "ok": true, "latency_ms": 84, "detail": "head block 1827400, 3s old" }
+        }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "wvm-data-retriever",
+            "description": "Retrieves WeaveVM calldata and blocks, verified against their Arweave archives.",
+            "version": env!("CARGO_PKG_VERSION"),
+            "license": { "name": "MIT" }
+        },
+        "servers": [{ "url": "https://gateway.wvm.dev" }],
+        "security": [{}, { "apiKey": [] }, { "bearer": [] }],
+        "paths": {
+            "/": {
+                "get": {
+                    "summary": "Greeting",
+                    "responses": with_access_errors(json!({ "200": text("Greeting", "WeaveGM!") }))
+                }
+            },
+            "/v1/calldata/{txid}": calldata_operation(
+                "HandlerGetCalldata",
+                "Calldata of a WeaveVM transaction",
+                vec![txid.clone()],
+                calldata_example(json!("")),
+            ),
+            "/v1/war-calldata/{txid}": calldata_operation(
+                "HandlerGetCalldata",
+                "Calldata of a WeaveVM transaction, decoded as a WeaveVM-Archiver block",
+                vec![txid.clone()],
+                calldata_example(json!(
+                    "{\"number\":\"0x1be248\",\"hash\":\"0x7ec5...\",\"transactions\":[\"0x9f6a...\"]}"
+                )),
+            ),
+            "/v1/calldata/tag/{tag1}/{tag2}": calldata_operation(
+                "HandlerGetCalldata",
+                "Calldata of the latest WeaveVM transaction with a tag",
+                vec![
+                    path_param("tag1", "Tag name", "Content-Type"),
+                    path_param("tag2", "Tag value", "text/plain"),
+                ],
+                calldata_example(json!("")),
+            ),
+            "/v2/calldata/{txid}": calldata_operation(
+                "CalldataResponseV2",
+                "Calldata of a WeaveVM transaction (v2 schema)",
+                vec![txid.clone()],
+                calldata_example_v2(Value::Null),
+            ),
+            "/v2/war-calldata/{txid}": calldata_operation(
+                "CalldataResponseV2",
+                "Calldata of a WeaveVM transaction with the decoded WeaveVM-Archiver block (v2 schema)",
+                vec![txid.clone()],
+                calldata_example_v2(json!({ "number": "0x1be248", "hash": "0x7ec5...", "transactions": ["0x9f6a..."] })),
+            ),
+            "/v2/calldata/tag/{tag1}/{tag2}": calldata_operation(
+                "CalldataResponseV2",
+                "Calldata of the latest WeaveVM transaction with a tag (v2 schema)",
+                vec![
+                    path_param("tag1", "Tag name", "Content-Type"),
+                    path_param("tag2", "Tag value", "text/plain"),
+                ],
+                calldata_example_v2(Value::Null),
+            ),
+            "/v1/block/{number}": {
+                "get": {
+                    "tags": ["blocks"],
+                    "summary": "Archived WeaveVM block",
+                    "description": "`raw` serves the brotli-compressed archive exactly as stored on Arweave.",
+                    "parameters": [
+                        {
+                            "name": "number",
+                            "in": "path",
+                            "required": true,
+                            "schema": { "type": "integer", "format": "uint64", "minimum": 0 },
+                            "example": 1827400
+                        },
+                        { "$ref": "#/components/parameters/format" }
+                    ],
+                    "responses": with_access_errors(json!({
+                        "200": negotiated("The archived block", block, Value::Null),
+                        "304": { "description": "`If-None-Match` matched the `ETag`" },
+                        "400": error("BadFormat"),
+                        "404": { "description": "The block has no known archive" },
+                        "406": error("NotAcceptable"),
+                        "502": text("The archive couldn't be downloaded or decoded", "archive download failed")
+                    }))
+                }
+            },
+            "/graphql": {
+                "get": {
+                    "tags": ["graphql"],
+                    "summary": "GraphiQL playground",
+                    "responses": with_access_errors(json!({
+                        "200": { "description": "GraphiQL page", "content": { "text/html": {} } }
+                    }))
+                },
+                "post": {
+                    "tags": ["graphql"],
+                    "summary": "Arweave-compatible GraphQL over WeaveVM transactions",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/GraphQLRequest" },
+                            "example": { "query": "{ transaction(id: \"0x...\") { id tags { name value } } }" }
+                        } }
+                    },
+                    "responses": with_access_errors(json!({
+                        "200": json_body("GraphQL response", json!({ "type": "object" }), json!({ "data": { "transaction": null } }))
+                    }))
+                }
+            },
+            "/v1/rpc": {
+                "post": {
+                    "tags": ["rpc"],
+                    "summary": "Archive-backed Ethereum JSON-RPC",
+                    "description": "Serves `eth_getTransactionByHash`, `eth_getBlockByNumber`, `eth_getBlockByHash` and `eth_getTransactionByBlockNumberAndIndex` from the Arweave archives. Accepts single and batch requests.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/JsonRpcRequest" },
+                            "example": { "jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x1be248", true] }
+                        } }
+                    },
+                    "responses": with_access_errors(json!({
+                        "200": json_body(
+                            "JSON-RPC response, errors included",
+                            json!({ "type": "object" }),
+                            json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32601, "message": "method not found" } }),
+                        )
+                    }))
+                }
+            },
+            "/v1/cache/stats": {
+                "get": {
+                    "summary": "In-memory cache statistics",
+                    "responses": with_access_errors(json!({
+                        "200": json_body(
+                            "Hits, misses and entries per cache",
+                            cache_stats,
+                            json!({
+                                "blocks": { "hits": 120, "misses": 8, "entries": 8 },
+                                "archive_ids": { "hits": 300, "misses": 12, "entries": 12 }
+                            }),
+                        )
+                    }))
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Prometheus metrics",
+                    "responses": { "200": text("Prometheus text format", "wvm_archive_missing_total 0") }
+                }
+            },
+            "/health/live": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Liveness probe",
+                    "responses": { "200": json_body("The process serves requests", json!({ "type": "object" }), json!({ "status": "live" })) }
+                }
+            },
+            "/health/ready": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Readiness probe",
+                    "responses": {
+                        "200": json_body("Every dependency check passes", readiness.clone(), readiness_example.clone()),
+                        "503": json_body("A dependency check failed", readiness, readiness_example)
+                    }
+                }
+            },
+            "/v1/usage": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Usage ledger of the calling API key",
+                    "security": [{ "apiKey": [] }, { "bearer": [] }],
+                    "responses": {
+                        "200": json_body(
+                            "Requests per UTC day and route",
+                            usage.clone(),
+                            json!([{ "date": "2026-10-19", "route": "/v1/war-calldata/:txid", "requests": 1520 }]),
+                        ),
+                        "401": error("Unauthorized")
+                    }
+                }
+            },
+            "/v1/usage/{key_id}": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Usage ledger of any API key",
+                    "security": [{ "bearer": [] }],
+                    "parameters": [path_param("key_id", "API key id", "acme")],
+                    "responses": {
+                        "200": json_body("Requests per UTC day and route", usage, json!([])),
+                        "401": error("Unauthorized")
+                    }
+                }
+            },
+            "/v1/incidents": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Logged archive/DA discrepancies, newest first",
+                    "security": [{ "bearer": [] }],
+                    "parameters": [
+                        {
+                            "name": "status",
+                            "in": "query",
+                            "required": false,
+                            "schema": { "type": "string", "enum": ["open", "acknowledged", "all"], "default": "open" }
+                        },
+                        { "name": "txid", "in": "query", "required": false, "schema": { "type": "string" } },
+                        {
+                            "name": "kind",
+                            "in": "query",
+                            "required": false,
+                            "schema": { "type": "string", "enum": ["mismatch", "missing_archive"] }
+                        },
+                        {
+                            "name": "before",
+                            "in": "query",
+                            "required": false,
+                            "description": "Only incidents with a smaller id, to page through older ones.",
+                            "schema": { "type": "integer" }
+                        },
+                        { "name": "limit", "in": "query", "required": false, "schema": { "type": "integer", "default": 100 } }
+                    ],
+                    "responses": {
+                        "200": json_body("Incidents", incidents, json!([incident_example(Value::Null, Value::Null)])),
+                        "403": error("AdminRequired")
+                    }
+                }
+            },
+            "/v1/incidents/{id}/ack": {
+                "post": {
+                    "tags": ["operations"],
+                    "summary": "Acknowledge an incident",
+                    "security": [{ "bearer": [] }],
+                    "parameters": [path_param("id", "Incident id", "17")],
+                    "requestBody": {
+                        "required": false,
+                        "content": {
+                            "application/json": {
+                                "schema": { "type": "object", "properties": { "note": { "type": "string" } } },
+                                "example": { "note": "re-archived block 2191540" }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": json_body(
+                            "The acknowledged incident, with its first acknowledgement if it already was",
+                            incident,
+                            incident_example(json!(1792375200), json!("re-archived block 2191540")),
+                        ),
+                        "403": error("AdminRequired"),
+                        "404": text("No incident with that id", "no incident 17")
+                    }
+                }
+            },
+            "/openapi.json": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "This document",
+                    "responses": { "200": { "description": "OpenAPI 3 document", "content": { "application/json": {} } } }
+                }
+            },
+            "/docs": {
+                "get": {
+                    "tags": ["operations"],
+                    "summary": "Redoc rendering of this document",
+                    "responses": { "200": { "description": "Redoc page", "content": { "text/html": {} } } }
+                }
+            }
+        },
+        "components": {
+            "schemas": schemas,
+            "parameters": {
+                "format": {
+                    "name": "format",
+                    "in": "query",
+                    "required": false,
+                    "description": "Output format, overrides `Accept`. `raw` is the calldata itself, or for blocks the archive as stored on Arweave.",
+                    "schema": { "type": "string", "enum": ["json", "cbor", "msgpack", "borsh", "raw"] }
+                },
+                "policy": {
+                    "name": "policy",
+                    "in": "query",
+                    "required": false,
+                    "description": "Source of the served calldata: `prefer_archive` (default), `prefer_rpc`, `archive_only`, `rpc_only`, `require_match` (both sources must agree) or `quorum:<n>` (at least n sources must agree).",
+                    "schema": { "type": "string", "pattern": "^(prefer_archive|prefer_rpc|archive_only|rpc_only|require_match|quorum:[12])$" }
+                },
+                "debug": {
+                    "name": "debug",
+                    "in": "query",
+                    "required": false,
+                    "description": "Adds a `provenance` object (see the `Provenance` schema) to JSON bodies, or an `x-wvm-provenance` JSON header to other formats and errors. Debug responses are `Cache-Control: no-store`.",
+                    "schema": { "type": "boolean" }
+                },
+                "debugHeader": {
+                    "name": "x-wvm-debug",
+                    "in": "header",
+                    "required": false,
+                    "description": "Same as `?debug=true` when `true` or `1`, `?debug=` takes precedence.",
+                    "schema": { "type": "string" }
+                }
+            },
+            "responses": {
+                "BadFormat": text("Unknown `?format=` value", "unsupported format xml, use json, cbor, msgpack, borsh or raw"),
+                "BadRequest": text("Malformed txid, or unknown `?format=` or `?policy=` value", "unsupported policy newest, use prefer_archive, prefer_rpc, archive_only, rpc_only, require_match or quorum:<n>"),
+                "NotFound": text("Unknown tx or tag, or a source the `?policy=` needs has no calldata for the tx", "transaction 0x9f6a... not found"),
+                "PolicyConflict": {
+                    "description": "The sources disagree: the diff for `require_match`, a text message for `quorum:<n>`",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "error": { "type": "string" },
+                                    "diff": { "$ref": "#/components/schemas/CalldataDiff" }
+                                }
+                            },
+                            "example": {
+                                "error": "archived and rpc calldata differ at byte 4",
+                                "diff": {
+                                    "archive_len": 8,
+                                    "rpc_len": 8,
+                                    "first_difference": 4,
+                                    "archive_keccak256": "0x3f1c...",
+                                    "rpc_keccak256": "0x8a0e...",
+                                    "kind": "content",
+                                    "tx_hash_matches": true
+                                }
+                            }
+                        },
+                        "text/plain": { "schema": { "type": "string" }, "example": "no quorum: 1 of 2 required sources agree" }
+                    }
+                },
+                "NotAcceptable": text("No supported type in `Accept`", "supported: application/json, application/cbor, application/msgpack, application/x-borsh, application/octet-stream"),
+                "Unauthorized": text("Unknown or missing API key", "unknown API key"),
+                "AdminRequired": text("The admin token is required as bearer token", "admin token required"),
+                "TooManyRequests": {
+                    "description": "Rate limit or daily quota exhausted, retry after `Retry-After` seconds",
+                    "headers": { "Retry-After": { "schema": { "type": "integer" } } },
+                    "content": { "text/plain": { "schema": { "type": "string" }, "example": "rate limit exceeded" } }
+                },
+                "RetrievalFailed": text("The transaction couldn't be retrieved", "transaction lookup timed out"),
+                "UpstreamRejected": {
+                    "description": "An upstream's circuit is open or its queue is full, retry after `Retry-After` seconds",
+                    "headers": { "Retry-After": { "schema": { "type": "integer" } } },
+                    "content": { "text/plain": { "schema": { "type": "string" }, "example": "upstream unavailable: wvm_rpc" } }
+                }
+            },
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": "x-api-key" },
+                "bearer": { "type": "http", "scheme": "bearer" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(method, path)` of every route registered in `main.rs`, with axum's
+    /// `:param` segments in OpenAPI's `{param}` form.
+    fn router_routes() -> Vec<(String, String)> {
+        let main = include_str!("../main.rs");
+        let mut routes = Vec::new();
+        for route in main.split(".route(").skip(1) {
+            let route = route.split(".layer(").next().unwrap();
+            let route = route.split(".route_layer(").next().unwrap();
+            let path = route.split('"').nth(1).unwrap();
+            let path = path
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{}}}", param),
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            for method in ["get", "post"] {
+                if route.contains(&format!("{}(", method)) {
+                    routes.push((method.to_string(), path.clone()));
+                }
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_document_matches_router() {
+        let paths = document()["paths"].as_object().unwrap();
+        let routes = router_routes();
+        assert!(routes.len() > 10);
+        for (method, path) in &routes {
+            assert!(
+                paths.get(path).and_then(|item| item.get(method)).is_some(),
+                "{} {} is not documented",
+                method,
+                path
+            );
+        }
+        for (path, item) in paths {
+            for method in item.as_object().unwrap().keys() {
+                assert!(
+                    routes.contains(&(method.clone(), path.clone())),
+                    "{} {} is documented but not routed",
+                    method,
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_schemas_are_generated_from_types() {
+        let schemas = &document()["components"]["schemas"];
+        let calldata = &schemas["HandlerGetCalldata"]["properties"];
+        assert_eq!(calldata["wvm_block_id"]["type"], "integer");
+        assert_eq!(calldata["war_decoded_calldata"]["nullable"], true);
+        assert!(schemas["Block"]["properties"]["transactions"].is_object());
+        assert!(schemas["Readiness"]["properties"]["checks"].is_object());
+
+        // every reference resolves
+        let text = document().to_string();
+        for reference in text.split("\"$ref\":\"").skip(1) {
+            let reference = reference.split('"').next().unwrap();
+            let mut target = document();
+            for part in reference.trim_start_matches("#/").split('/') {
+                target = &target[part];
+            }
+            assert!(!target.is_null(), "dangling {}", reference);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use crate::utils::arweave::{archived_tx_hash_matches, find_tx_calldata_in_archive};
+use crate::utils::arweave_gql::retrieve_block_from_arweave;
+use crate::utils::config::StageTimeouts;
+use crate::utils::constants::WVM_RPC_URL;
+use crate::utils::index::{get_archive_id, get_block_hash, get_tx_location, TxLocation};
+use crate::utils::policy::CalldataDiff;
+use crate::utils::provenance::{
+    record_arweave_id, record_rpc_endpoint, record_stage, Stage, StageTiming,
+};
+use crate::utils::schemas::GetBlockFromTx;
+use crate::utils::upstream::is_upstream_rejection;
+use crate::utils::wvm_client::{
+    retrieve_wvm_block_ref_from_txtag, try_retrieve_wvm_block_ref_from_txid,
+};
+use anyhow::Error;
+use ethers::types::U256;
+use reth_primitives::{Bytes, B256};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::time::timeout;
+
+/// What a calldata retrieval is looking for.
+#[derive(Debug, Clone)]
+pub enum TxSelector {
+    TxId(String),
+    Tag([String; 2]),
+}
+
+/// Everything the calldata endpoints need about one transaction.
+#[derive(Debug, Clone)]
+pub struct Retrieval {
+    pub txid: B256,
+    pub block_ref: GetBlockFromTx,
+    /// Arweave id of the block archive, empty when it couldn't be resolved.
+    pub arweave_id: String,
+    /// Calldata as served by the WeaveVM RPC, empty when the RPC didn't answer.
+    pub wvm_calldata: Bytes,
+    /// Whether the WeaveVM RPC served the tx, i.e. its data is available on
+    /// the DA layer. `false` in archive-only mode.
+    pub da_available: bool,
+    /// What the archive side established about the tx.
+    pub archive: ArchiveState,
+    /// Calldata found in the archived block, `None` when missing.
+    pub arweave_calldata: Option<Bytes>,
+    /// How the archived calldata differs from the RPC's, `None` unless both
+    /// are available and differ.
+    pub mismatch: Option<CalldataDiff>,
+}
+
+/// Whether the tx's block archive was read, and what it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveState {
+    /// The decoded archive contains the tx.
+    Archived,
+    /// The archive was decoded and the tx isn't in it.
+    Absent,
+    /// No archive exists for the block yet, archiving lags behind the chain.
+    NotYetArchived,
+    /// The archive couldn't be resolved, downloaded or decoded in time, so
+    /// whether it holds the tx is unknown.
+    Unavailable,
+}
+
+/// A retrieval no upstream can satisfy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetrievalError {
+    /// The txid isn't a 32-byte hex hash.
+    InvalidTxid(String),
+    /// Neither the RPC nor the local index know the tx, or no tx carries the
+    /// tag.
+    NotFound(String),
+}
+
+impl fmt::Display for RetrievalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetrievalError::InvalidTxid(txid) => write!(f, "invalid transaction hash {}", txid),
+            RetrievalError::NotFound(what) => write!(f, "{} not found", what),
+        }
+    }
+}
+
+impl std::error::Error for RetrievalError {}
+
+impl RetrievalError {
+    /// The unsatisfiable retrieval behind `err`, if any.
+    pub fn find(err: &Error) -> Option<&RetrievalError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+/// Shared retrieval pipeline behind the calldata, war-calldata and tag
+/// endpoints.
+///
+/// Only independent stages run concurrently: when the local index already
+/// knows where a tx lives, its archive is downloaded while the RPC lookup is
+/// still in flight. For a tx the index doesn't know, the lookup, the storage
+/// proof and the download each need the previous one's answer and run in
+/// turn; only the index fallback is read alongside the proof. Every stage is
+/// bounded by its `StageTimeouts` entry; a stage that times out is treated
+/// like one that failed. An upstream rejecting a stage (open circuit, full
+/// queue) fails the retrieval instead, unless another source can stand in
+/// for it.
+#[tracing::instrument]
+pub async fn retrieve(selector: TxSelector) -> Result<Retrieval, Error> {
+    let timeouts = StageTimeouts::from_env();
+    let retrieval = match selector {
+        TxSelector::TxId(txid) => {
+            let txid = B256::from_str(txid.trim())
+                .map_err(|_| Error::new(RetrievalError::InvalidTxid(txid.clone())))?;
+            retrieve_by_txid(txid, timeouts).await?
+        }
+        TxSelector::Tag(tag) => {
+            let tag_id = format!("tag {}/{}", tag[0], tag[1]);
+            let (block_ref, txid) = tx_lookup(timeouts, retrieve_wvm_block_ref_from_txtag(tag))
+                .await
+                .map_err(|_| Error::msg("tag lookup timed out"))??
+                .ok_or_else(|| Error::new(RetrievalError::NotFound(tag_id)))?;
+            // a pending tx has no block, nor archive, yet
+            let (arweave_id, lookup) = match block_ref.hash {
+                Some(_) => archive_of_block(block_ref.number.as_u64(), txid, timeouts).await?,
+                None => (String::new(), (ArchiveState::NotYetArchived, None)),
+            };
+            assemble(txid, block_ref, arweave_id, lookup)
+        }
+    };
+    Ok(diagnose(retrieval, timeouts).await)
+}
+
+/// Fills in `mismatch` when the archived and RPC calldata differ, and logs it.
+async fn diagnose(mut retrieval: Retrieval, timeouts: StageTimeouts) -> Retrieval {
+    let archived = retrieval
+        .arweave_calldata
+        .as_ref()
+        .filter(|calldata| !calldata.is_empty());
+    let Some(mut diff) = archived
+        .filter(|_| retrieval.da_available)
+        .and_then(|archived| CalldataDiff::between(archived, &retrieval.wvm_calldata))
+    else {
+        return retrieval;
+    };
+    diff.tx_hash_matches = match timeout(
+        timeouts.archive_download,
+        archived_tx_hash_matches(&retrieval.arweave_id, retrieval.txid),
+    )
+    .await
+    {
+        Ok(Ok(matches)) => matches,
+        _ => None,
+    };
+    tracing::warn!(
+        txid = %retrieval.txid,
+        block = %retrieval.block_ref.number,
+        arweave_id = %retrieval.arweave_id,
+        archive_len = diff.archive_len,
+        rpc_len = diff.rpc_len,
+        first_difference = diff.first_difference,
+        archive_keccak256 = %diff.archive_keccak256,
+        rpc_keccak256 = %diff.rpc_keccak256,
+        kind = ?diff.kind,
+        tx_hash_matches = ?diff.tx_hash_matches,
+        "archived calldata differs from the DA calldata"
+    );
+    retrieval.mismatch = Some(diff);
+    retrieval
+}
+
+#[tracing::instrument(skip(timeouts))]
+async fn retrieve_by_txid(txid: B256, timeouts: StageTimeouts) -> Result<Retrieval, Error> {
+    let location = get_tx_location(&format!("{:#x}", txid)).await;
+    let prefetch = async {
+        match &location {
+            Some(location) => {
+                archive_lookup(find_tx_calldata_in_archive(&location.arweave_id, txid).await)
+            }
+            None => Ok((ArchiveState::Unavailable, None)),
+        }
+    };
+    let lookup = tx_lookup(timeouts, try_retrieve_wvm_block_ref_from_txid(txid));
+    let (from_rpc, prefetched) = tokio::join!(lookup, prefetch);
+    // why the RPC didn't place the tx in a block, `None` when it doesn't know it
+    let mut failure = None;
+    let from_rpc = match from_rpc {
+        Ok(Ok(block_ref)) if block_ref.hash.is_some() => Some(block_ref),
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => {
+            failure = Some(e);
+            None
+        }
+        Err(_) => {
+            failure = Some(Error::msg("transaction lookup timed out"));
+            None
+        }
+    };
+
+    match (from_rpc, location) {
+        (Some(block_ref), location) => {
+            let number = block_ref.number.as_u64();
+            let (arweave_id, lookup) = match (location, prefetched) {
+                (
+                    Some(TxLocation {
+                        block_number,
+                        arweave_id,
+                        ..
+                    }),
+                    Ok(lookup @ (ArchiveState::Archived, _)),
+                ) if block_number == number => {
+                    record_arweave_id(&arweave_id);
+                    (arweave_id, lookup)
+                }
+                _ => archive_of_block(number, txid, timeouts).await?,
+            };
+            Ok(assemble(txid, block_ref, arweave_id, lookup))
+        }
+        // archive-only mode: the RPC calldata is unknown and left empty
+        (None, Some(location)) => {
+            record_arweave_id(&location.arweave_id);
+            let block_hash = get_block_hash(location.block_number)
+                .await
+                .and_then(|hash| B256::from_str(&hash).ok());
+            let block_ref =
+                GetBlockFromTx::new(U256::from(location.block_number), block_hash, Bytes::new());
+            Ok(Retrieval {
+                da_available: false,
+                ..assemble(txid, block_ref, location.arweave_id, prefetched?)
+            })
+        }
+        // a failed lookup says nothing about whether the tx exists
+        (None, None) => match failure {
+            Some(failure) => Err(failure),
+            None => Err(Error::new(RetrievalError::NotFound(format!(
+                "transaction {:#x}",
+                txid
+            )))),
+        },
+    }
+}
+
+type ArchiveLookup = (ArchiveState, Option<Bytes>);
+
+/// Resolves the Arweave id of `block_number` (storage proof, then the local
+/// index) and looks `txid` up in its archive.
+#[tracing::instrument(skip(timeouts))]
+async fn archive_of_block(
+    block_number: u64,
+    txid: B256,
+    timeouts: StageTimeouts,
+) -> Result<(String, ArchiveLookup), Error> {
+    let proof = async {
+        match u32::try_from(block_number) {
+            Ok(number) => {
+                match timeout(timeouts.storage_proof, retrieve_block_from_arweave(number)).await {
+                    Ok(res) => unless_rejected(res.map(Some)),
+                    Err(_) => Ok(None),
+                }
+            }
+            Err(_) => Ok(None),
+        }
+    };
+    let (from_proof, indexed) = tokio::join!(proof, get_archive_id(block_number));
+    let arweave_id = match archive_id(from_proof, indexed)? {
+        Ok(arweave_id) => arweave_id,
+        Err(state) => return Ok((String::new(), (state, None))),
+    };
+    record_arweave_id(&arweave_id);
+    let lookup = archive_lookup(find_tx_calldata_in_archive(&arweave_id, txid).await)?;
+    Ok((arweave_id, lookup))
+}
+
+/// The Arweave id of a block from its storage proof (`None` when the proof
+/// couldn't be had) or else the local index, or why there is none.
+fn archive_id(
+    from_proof: Result<Option<String>, Error>,
+    indexed: Option<String>,
+) -> Result<Result<String, ArchiveState>, Error> {
+    match (from_proof, indexed) {
+        (Ok(Some(arweave_id)), _) if !arweave_id.is_empty() => Ok(Ok(arweave_id)),
+        (_, Some(arweave_id)) => Ok(Ok(arweave_id)),
+        (Err(rejection), None) => Err(rejection),
+        // the proof answered, without an archive
+        (Ok(Some(_)), None) => Ok(Err(ArchiveState::NotYetArchived)),
+        (Ok(None), None) => Ok(Err(ArchiveState::Unavailable)),
+    }
+}
+
+/// Tells an archive that was read and lacks the tx from one that couldn't be
+/// read. Upstream rejections fail the retrieval.
+fn archive_lookup(res: Result<Option<Bytes>, Error>) -> Result<ArchiveLookup, Error> {
+    match res {
+        Ok(Some(calldata)) => Ok((ArchiveState::Archived, Some(calldata))),
+        Ok(None) => Ok((ArchiveState::Absent, None)),
+        Err(e) if is_upstream_rejection(&e) => Err(e),
+        Err(_) => Ok((ArchiveState::Unavailable, None)),
+    }
+}
+
+/// Bounds a WeaveVM RPC lookup by `timeouts.tx_lookup` and records it, timed
+/// out or not.
+async fn tx_lookup<T>(
+    timeouts: StageTimeouts,
+    lookup: impl Future<Output = T>,
+) -> Result<T, tokio::time::error::Elapsed> {
+    let started = Instant::now();
+    record_rpc_endpoint(WVM_RPC_URL);
+    let res = timeout(timeouts.tx_lookup, lookup).await;
+    record_stage(StageTiming::since(Stage::TxLookup, None, started));
+    res
+}
+
+/// Failed stages count as missing data, except upstream rejections.
+fn unless_rejected<T>(res: Result<Option<T>, Error>) -> Result<Option<T>, Error> {
+    match res {
+        Err(e) if !is_upstream_rejection(&e) => Ok(None),
+        res => res,
+    }
+}
+
+fn assemble(
+    txid: B256,
+    block_ref: GetBlockFromTx,
+    arweave_id: String,
+    (archive, arweave_calldata): ArchiveLookup,
+) -> Retrieval {
+    Retrieval {
+        txid,
+        wvm_calldata: block_ref.calldata.clone(),
+        da_available: true,
+        block_ref,
+        arweave_id,
+        archive,
+        arweave_calldata,
+        mismatch: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::upstream::UpstreamUnavailable;
+
+    #[test]
+    fn test_archive_id() {
+        let id = |from_proof, indexed: Option<&str>| {
+            archive_id(from_proof, indexed.map(str::to_string)).unwrap()
+        };
+        assert_eq!(
+            id(Ok(Some("proved".to_string())), Some("indexed")),
+            Ok("proved".to_string())
+        );
+        assert_eq!(id(Ok(None), Some("indexed")), Ok("indexed".to_string()));
+        // archiving lags: the proof answered without an archive
+        assert_eq!(
+            id(Ok(Some(String::new())), None),
+            Err(ArchiveState::NotYetArchived)
+        );
+        // the proof timed out or failed: no telling whether there is one
+        assert_eq!(id(Ok(None), None), Err(ArchiveState::Unavailable));
+
+        let rejected = Error::new(UpstreamUnavailable {
+            upstream: "wvm-rpc".to_string(),
+        });
+        assert!(archive_id(Err(rejected), None).is_err());
+    }
+
+    #[test]
+    fn test_archive_lookup() {
+        let calldata = Bytes::from_static(&[0xde, 0xad]);
+        assert_eq!(
+            archive_lookup(Ok(Some(calldata.clone()))).unwrap(),
+            (ArchiveState::Archived, Some(calldata))
+        );
+        assert_eq!(
+            archive_lookup(Ok(None)).unwrap(),
+            (ArchiveState::Absent, None)
+        );
+        // a download that timed out says nothing about the archive's content
+        assert_eq!(
+            archive_lookup(Err(Error::msg("archive download timed out"))).unwrap(),
+            (ArchiveState::Unavailable, None)
+        );
+        let rejected = Error::new(UpstreamUnavailable {
+            upstream: "arweave".to_string(),
+        });
+        assert!(archive_lookup(Err(rejected)).is_err());
+    }
+}
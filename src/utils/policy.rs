@@ -0,0 +1,367 @@
+use crate::utils::schemas::borsh_bytes;
+use axum::http::StatusCode;
+use borsh_derive::BorshSerialize;
+use ethers::utils::keccak256;
+use reth_primitives::{Bytes, B256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Sources a calldata can come from: the Arweave archive and the WeaveVM RPC.
+pub const SOURCES: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Archive,
+    Rpc,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Archive => write!(f, "arweave archive"),
+            Source::Rpc => write!(f, "wvm rpc"),
+        }
+    }
+}
+
+/// Which source the calldata routes serve, and what they require of the
+/// other one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourcePolicy {
+    /// The archived calldata, the RPC's when it isn't archived.
+    #[default]
+    PreferArchive,
+    /// The RPC's calldata, the archived one when the RPC didn't serve the tx.
+    PreferRpc,
+    ArchiveOnly,
+    RpcOnly,
+    /// Calldata only when both sources have it and agree.
+    RequireMatch,
+    /// Calldata at least `n` sources agree on.
+    Quorum(usize),
+}
+
+impl FromStr for SourcePolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.trim().to_ascii_lowercase().replace('-', "_");
+        let policy = match name.as_str() {
+            "prefer_archive" => SourcePolicy::PreferArchive,
+            "prefer_rpc" => SourcePolicy::PreferRpc,
+            "archive_only" => SourcePolicy::ArchiveOnly,
+            "rpc_only" => SourcePolicy::RpcOnly,
+            "require_match" => SourcePolicy::RequireMatch,
+            _ => match name.strip_prefix("quorum:").map(str::parse::<usize>) {
+                Some(Ok(n)) if (1..=SOURCES).contains(&n) => SourcePolicy::Quorum(n),
+                Some(_) => return Err(format!("quorum must be between 1 and {}", SOURCES)),
+                None => {
+                    return Err(format!(
+                        "unsupported policy {}, use prefer_archive, prefer_rpc, archive_only, rpc_only, require_match or quorum:<n>",
+                        name
+                    ))
+                }
+            },
+        };
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for SourcePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourcePolicy::PreferArchive => write!(f, "prefer_archive"),
+            SourcePolicy::PreferRpc => write!(f, "prefer_rpc"),
+            SourcePolicy::ArchiveOnly => write!(f, "archive_only"),
+            SourcePolicy::RpcOnly => write!(f, "rpc_only"),
+            SourcePolicy::RequireMatch => write!(f, "require_match"),
+            SourcePolicy::Quorum(n) => write!(f, "quorum:{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PolicyQuery {
+    pub policy: Option<String>,
+}
+
+/// `?policy=`, `PreferArchive` when absent.
+pub fn policy_from_query(query: &PolicyQuery) -> Result<SourcePolicy, (StatusCode, String)> {
+    match &query.policy {
+        Some(name) => name.parse().map_err(|e| (StatusCode::BAD_REQUEST, e)),
+        None => Ok(SourcePolicy::default()),
+    }
+}
+
+/// How the archived and the RPC calldata differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    /// One is cut short: it is a prefix of the other.
+    Truncated,
+    /// One is the hex text of the other's bytes.
+    HexEncoding,
+    /// The bytes themselves differ.
+    Content,
+}
+
+/// Where the archived and the RPC calldata part ways.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, JsonSchema)]
+pub struct CalldataDiff {
+    pub archive_len: usize,
+    pub rpc_len: usize,
+    /// Offset of the first differing byte, the shorter length when one is a
+    /// prefix of the other.
+    pub first_difference: usize,
+    #[borsh(serialize_with = "borsh_bytes::b256")]
+    #[schemars(with = "String")]
+    pub archive_keccak256: B256,
+    #[borsh(serialize_with = "borsh_bytes::b256")]
+    #[schemars(with = "String")]
+    pub rpc_keccak256: B256,
+    pub kind: MismatchKind,
+    /// Whether the archived tx, re-hashed from its signed encoding, still
+    /// hashes to the tx id the RPC served. `null` when it wasn't checked.
+    pub tx_hash_matches: Option<bool>,
+}
+
+impl CalldataDiff {
+    /// `None` when both are equal.
+    pub fn between(archived: &[u8], served: &[u8]) -> Option<CalldataDiff> {
+        if archived == served {
+            return None;
+        }
+        let first_difference = archived
+            .iter()
+            .zip(served)
+            .position(|(a, b)| a != b)
+            .unwrap_or(archived.len().min(served.len()));
+        Some(CalldataDiff {
+            archive_len: archived.len(),
+            rpc_len: served.len(),
+            first_difference,
+            archive_keccak256: B256::from(keccak256(archived)),
+            rpc_keccak256: B256::from(keccak256(served)),
+            kind: mismatch_kind(archived, served),
+            tx_hash_matches: None,
+        })
+    }
+}
+
+fn mismatch_kind(archived: &[u8], served: &[u8]) -> MismatchKind {
+    if archived.starts_with(served) || served.starts_with(archived) {
+        MismatchKind::Truncated
+    } else if hex_encodes(archived, served) || hex_encodes(served, archived) {
+        MismatchKind::HexEncoding
+    } else {
+        MismatchKind::Content
+    }
+}
+
+/// Whether `text` is the hex text of `bytes`, with or without `0x`.
+fn hex_encodes(text: &[u8], bytes: &[u8]) -> bool {
+    let digits = text
+        .strip_prefix(b"0x")
+        .or(text.strip_prefix(b"0X"))
+        .unwrap_or(text);
+    ethers::utils::hex::decode(digits).is_ok_and(|decoded| decoded == bytes)
+}
+
+/// A policy the retrieved sources don't satisfy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    /// A source the policy needs has no calldata for the tx.
+    Missing {
+        policy: SourcePolicy,
+        source: Source,
+    },
+    Mismatch(CalldataDiff),
+    NoQuorum {
+        required: usize,
+        agreeing: usize,
+    },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Missing { policy, source } => {
+                write!(
+                    f,
+                    "policy {} needs the {}, which has no calldata for the tx",
+                    policy, source
+                )
+            }
+            PolicyError::Mismatch(diff) => write!(
+                f,
+                "archived and rpc calldata differ at byte {}",
+                diff.first_difference
+            ),
+            PolicyError::NoQuorum { required, agreeing } => write!(
+                f,
+                "no quorum: {} of {} required sources agree",
+                agreeing, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl PolicyError {
+    /// The unsatisfied policy behind `err`, if any.
+    pub fn find(err: &anyhow::Error) -> Option<&PolicyError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+impl SourcePolicy {
+    /// Picks the calldata to serve from the `archived` copy and the one
+    /// `served` by the RPC, `None` meaning the source doesn't have it.
+    /// The preferring policies never fail and return `None` when neither
+    /// source has the calldata.
+    pub fn select(
+        self,
+        archived: Option<&Bytes>,
+        served: Option<&Bytes>,
+    ) -> Result<Option<Bytes>, PolicyError> {
+        let missing = |source| PolicyError::Missing {
+            policy: self,
+            source,
+        };
+        match self {
+            SourcePolicy::PreferArchive => Ok(archived.or(served).cloned()),
+            SourcePolicy::PreferRpc => Ok(served.or(archived).cloned()),
+            SourcePolicy::ArchiveOnly => {
+                archived.cloned().map(Some).ok_or(missing(Source::Archive))
+            }
+            SourcePolicy::RpcOnly => served.cloned().map(Some).ok_or(missing(Source::Rpc)),
+            SourcePolicy::RequireMatch => {
+                let archived = archived.ok_or(missing(Source::Archive))?;
+                let served = served.ok_or(missing(Source::Rpc))?;
+                match CalldataDiff::between(archived, served) {
+                    Some(diff) => Err(PolicyError::Mismatch(diff)),
+                    None => Ok(Some(archived.clone())),
+                }
+            }
+            SourcePolicy::Quorum(required) => {
+                // sources in order of preference, the first breaks ties
+                let candidates = [archived, served].into_iter().flatten().collect::<Vec<_>>();
+                let best = candidates
+                    .iter()
+                    .map(|candidate| {
+                        let agreeing = candidates
+                            .iter()
+                            .filter(|other| other == &candidate)
+                            .count();
+                        (agreeing, *candidate)
+                    })
+                    .rev()
+                    .max_by_key(|(agreeing, _)| *agreeing);
+                match best {
+                    Some((agreeing, calldata)) if agreeing >= required => {
+                        Ok(Some(calldata.clone()))
+                    }
+                    best => Err(PolicyError::NoQuorum {
+                        required,
+                        agreeing: best.map_or(0, |(agreeing, _)| agreeing),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("prefer-rpc".parse(), Ok(SourcePolicy::PreferRpc));
+        assert_eq!(" REQUIRE_MATCH".parse(), Ok(SourcePolicy::RequireMatch));
+        assert_eq!("quorum:2".parse(), Ok(SourcePolicy::Quorum(2)));
+        assert!("quorum:3".parse::<SourcePolicy>().is_err());
+        assert!("quorum:0".parse::<SourcePolicy>().is_err());
+        assert!("newest".parse::<SourcePolicy>().is_err());
+        for policy in [SourcePolicy::ArchiveOnly, SourcePolicy::Quorum(1)] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+    }
+
+    #[test]
+    fn test_calldata_diff() {
+        let diff = CalldataDiff::between(&[0xde, 0xad, 0xbe], &[0xde, 0xaf]).unwrap();
+        assert_eq!(
+            (diff.archive_len, diff.rpc_len, diff.first_difference),
+            (3, 2, 1)
+        );
+        assert_eq!(diff.kind, MismatchKind::Content);
+        assert_ne!(diff.archive_keccak256, diff.rpc_keccak256);
+        assert_eq!(diff.tx_hash_matches, None);
+        assert_eq!(CalldataDiff::between(b"0xab", b"0xab"), None);
+
+        let kind =
+            |archived: &[u8], served: &[u8]| CalldataDiff::between(archived, served).unwrap().kind;
+        assert_eq!(kind(&[0xde, 0xad, 0xbe], &[0xde]), MismatchKind::Truncated);
+        assert_eq!(kind(&[], &[0xde]), MismatchKind::Truncated);
+        assert_eq!(kind(&[0xde, 0xad], b"0xdead"), MismatchKind::HexEncoding);
+        assert_eq!(kind(b"DEAD", &[0xde, 0xad]), MismatchKind::HexEncoding);
+        assert_eq!(kind(&[0xde, 0xad], &[0xbe, 0xef]), MismatchKind::Content);
+    }
+
+    #[test]
+    fn test_select() {
+        let archived = Bytes::from_static(&[0xde, 0xad, 0xbe]);
+        let served = Bytes::from_static(&[0xde, 0xaf]);
+
+        assert_eq!(
+            SourcePolicy::PreferArchive.select(Some(&archived), Some(&served)),
+            Ok(Some(archived.clone()))
+        );
+        assert_eq!(
+            SourcePolicy::PreferRpc.select(Some(&archived), Some(&served)),
+            Ok(Some(served.clone()))
+        );
+        assert_eq!(SourcePolicy::PreferRpc.select(None, None), Ok(None));
+        assert_eq!(
+            SourcePolicy::RpcOnly.select(Some(&archived), None),
+            Err(PolicyError::Missing {
+                policy: SourcePolicy::RpcOnly,
+                source: Source::Rpc
+            })
+        );
+        assert_eq!(
+            SourcePolicy::RequireMatch.select(Some(&archived), Some(&served)),
+            Err(PolicyError::Mismatch(
+                CalldataDiff::between(&archived, &served).unwrap()
+            ))
+        );
+        assert_eq!(
+            SourcePolicy::RequireMatch.select(Some(&archived), Some(&archived)),
+            Ok(Some(archived.clone()))
+        );
+
+        assert_eq!(
+            SourcePolicy::Quorum(1).select(Some(&archived), Some(&served)),
+            Ok(Some(archived.clone()))
+        );
+        assert_eq!(
+            SourcePolicy::Quorum(1).select(None, Some(&served)),
+            Ok(Some(served.clone()))
+        );
+        assert_eq!(
+            SourcePolicy::Quorum(2).select(Some(&archived), Some(&served)),
+            Err(PolicyError::NoQuorum {
+                required: 2,
+                agreeing: 1
+            })
+        );
+        assert_eq!(
+            SourcePolicy::Quorum(2).select(Some(&served), Some(&served)),
+            Ok(Some(served.clone()))
+        );
+    }
+}
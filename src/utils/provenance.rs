@@ -0,0 +1,157 @@
+use axum::http::HeaderMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+pub const DEBUG_HEADER: &str = "x-wvm-debug";
+/// Carries the provenance of formats without room for it in the body.
+pub const PROVENANCE_HEADER: &str = "x-wvm-provenance";
+
+tokio::task_local! {
+    static PROVENANCE: RefCell<Provenance>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    TxLookup,
+    StorageProof,
+    Download,
+    Decompress,
+    Decode,
+    Compare,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheOutcome {
+    Hit,
+    Miss,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StageTiming {
+    pub stage: Stage,
+    /// `None` for stages that aren't cached.
+    pub cache: Option<CacheOutcome>,
+    pub latency_ms: f64,
+}
+
+impl StageTiming {
+    pub fn since(stage: Stage, cache: Option<CacheOutcome>, started: Instant) -> Self {
+        StageTiming::new(stage, cache, started.elapsed())
+    }
+
+    pub fn new(stage: Stage, cache: Option<CacheOutcome>, latency: Duration) -> Self {
+        StageTiming {
+            stage,
+            cache,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// Sources a calldata retrieval consulted and how long each stage took, in
+/// the order the stages finished.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Provenance {
+    pub rpc_endpoint: Option<String>,
+    /// Gateway the archive was downloaded from, `None` when it was cached.
+    pub gateway: Option<String>,
+    pub arweave_id: Option<String>,
+    pub stages: Vec<StageTiming>,
+}
+
+/// Runs `future` while recording its provenance. Stages of work shared with
+/// concurrent requests (single-flight downloads) are reported to each of
+/// them with the timings of the shared work.
+pub async fn with_provenance<F: Future>(future: F) -> (F::Output, Provenance) {
+    PROVENANCE
+        .scope(RefCell::new(Provenance::default()), async {
+            let output = future.await;
+            (output, PROVENANCE.with(RefCell::take))
+        })
+        .await
+}
+
+/// No-op outside of `with_provenance`.
+fn update(f: impl FnOnce(&mut Provenance)) {
+    let _ = PROVENANCE.try_with(|provenance| f(&mut provenance.borrow_mut()));
+}
+
+pub fn record_stage(timing: StageTiming) {
+    update(|provenance| provenance.stages.push(timing));
+}
+
+pub fn record_rpc_endpoint(url: &str) {
+    update(|provenance| provenance.rpc_endpoint = Some(url.to_string()));
+}
+
+pub fn record_gateway(url: &str) {
+    update(|provenance| provenance.gateway = Some(url.to_string()));
+}
+
+pub fn record_arweave_id(arweave_id: &str) {
+    if !arweave_id.is_empty() {
+        update(|provenance| provenance.arweave_id = Some(arweave_id.to_string()));
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DebugQuery {
+    pub debug: Option<bool>,
+}
+
+/// `?debug=true` or an `x-wvm-debug: true` (or `1`) header.
+pub fn debug_requested(headers: &HeaderMap, query: &DebugQuery) -> bool {
+    query.debug.unwrap_or_else(|| {
+        headers
+            .get(DEBUG_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| matches!(value.trim(), "1" | "true"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[tokio::test]
+    async fn test_with_provenance() {
+        // recording outside of a scope is ignored
+        record_arweave_id("ignored");
+
+        let ((), provenance) = with_provenance(async {
+            record_rpc_endpoint("https://rpc");
+            record_arweave_id("");
+            record_arweave_id("arweave-id");
+            tokio::task::yield_now().await;
+            record_stage(StageTiming::new(
+                Stage::Download,
+                Some(CacheOutcome::Miss),
+                Duration::from_millis(3),
+            ));
+        })
+        .await;
+        assert_eq!(provenance.rpc_endpoint.as_deref(), Some("https://rpc"));
+        assert_eq!(provenance.arweave_id.as_deref(), Some("arweave-id"));
+        assert_eq!(provenance.gateway, None);
+        assert_eq!(provenance.stages.len(), 1);
+        assert_eq!(provenance.stages[0].latency_ms, 3.0);
+    }
+
+    #[test]
+    fn test_debug_requested() {
+        let mut headers = HeaderMap::new();
+        assert!(!debug_requested(&headers, &DebugQuery::default()));
+        headers.insert(DEBUG_HEADER, HeaderValue::from_static("1"));
+        assert!(debug_requested(&headers, &DebugQuery::default()));
+        assert!(!debug_requested(
+            &headers,
+            &DebugQuery { debug: Some(false) }
+        ));
+    }
+}
@@ -1,8 +1,12 @@
+use crate::utils::limits::{limited_brotli_reader, DecodeLimits, LimitError};
+use crate::utils::policy::CalldataDiff;
 use axum::Error;
+use borsh::BorshDeserialize as _;
 use borsh_derive::{BorshDeserialize, BorshSerialize};
 use brotli::{self, Decompressor};
 use ethers::types::U256;
-use reth_primitives::SealedBlockWithSenders;
+use reth_primitives::{Address, Bytes, SealedBlockWithSenders, B256};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::io::Read;
 use wvm_borsh::block::BorshSealedBlockWithSenders;
@@ -10,27 +14,101 @@ use wvm_tx::wvm::WvmSealedBlockWithSenders;
 
 pub struct EncodingUtils;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// An archived block in an encoding version this retriever doesn't decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedBlockVersion;
+
+impl std::fmt::Display for UnsupportedBlockVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported archived block version")
+    }
+}
+
+impl std::error::Error for UnsupportedBlockVersion {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetBlockFromTx {
     pub number: U256,
-    pub hash: String,
-    pub calldata: String,
+    /// `None` while the tx is pending or when the RPC doesn't know it.
+    pub hash: Option<B256>,
+    pub calldata: Bytes,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// JSON body of the calldata, war-calldata and tag routes.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct HandlerGetCalldata {
+    /// `0x` hex calldata, from the Arweave archive when found there, else as
+    /// served by the WeaveVM RPC.
     pub calldata: String,
+    /// Arweave id of the archive of the tx's block, empty when unresolved.
     pub arweave_block_hash: String,
+    /// `0x` hex hash of the WeaveVM block, `0x` when unknown.
     pub wvm_block_hash: String,
     pub wvm_block_id: u32,
+    /// On the war route, the calldata decoded as a `WeaveVMArchiverBlock` and
+    /// serialized to a JSON string, `null` when it isn't one. `""` elsewhere.
     pub war_decoded_calldata: Option<String>,
     pub wvm_data_da: bool,
+    /// Whether the tx was found in its block's Arweave archive.
     pub ar_data_archive: bool,
+    /// Whether the archived calldata equals the WeaveVM RPC calldata.
     pub da_archive_is_equal_data: bool,
 }
 
+/// Format-independent calldata response. Serializes to the `HandlerGetCalldata`
+/// JSON shape, while binary formats carry `calldata` and `wvm_block_hash`
+/// (empty when unknown) as raw bytes.
+#[derive(Debug, Clone, Serialize, Deserialize, BorshSerialize)]
+pub struct CalldataResponse {
+    #[borsh(serialize_with = "borsh_bytes::bytes")]
+    pub calldata: Bytes,
+    pub arweave_block_hash: String,
+    #[borsh(serialize_with = "borsh_bytes::bytes")]
+    pub wvm_block_hash: Bytes,
+    pub wvm_block_id: u32,
+    pub war_decoded_calldata: Option<String>,
+    pub wvm_data_da: bool,
+    pub ar_data_archive: bool,
+    pub da_archive_is_equal_data: bool,
+}
+
+/// `/v2` calldata body. Unlike v1, DA availability and archive presence are
+/// reported separately, block numbers are u64, absent values are `null`
+/// and the war block is a nested object.
+#[derive(Debug, Serialize, Deserialize, BorshSerialize, JsonSchema)]
+pub struct CalldataResponseV2 {
+    #[borsh(serialize_with = "borsh_bytes::b256")]
+    #[schemars(with = "String")]
+    pub txid: B256,
+    /// Archived calldata when the tx was found in its archive, else the
+    /// calldata served by the WeaveVM RPC.
+    #[borsh(serialize_with = "borsh_bytes::opt_bytes")]
+    #[schemars(with = "Option<String>")]
+    pub calldata: Option<Bytes>,
+    /// `null` while the tx is pending or its block is unknown.
+    pub wvm_block_number: Option<u64>,
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    #[schemars(with = "Option<String>")]
+    pub wvm_block_hash: Option<B256>,
+    /// Arweave id of the archive of the tx's block.
+    pub arweave_id: Option<String>,
+    /// Whether the WeaveVM RPC served the tx.
+    pub wvm_data_da: bool,
+    /// Whether the tx was found in its block's Arweave archive.
+    pub ar_data_archive: bool,
+    /// Whether the archived calldata equals the RPC calldata, `null` unless
+    /// both are available.
+    pub da_archive_is_equal_data: Option<bool>,
+    /// On the war route, the calldata decoded as a WeaveVM-Archiver block,
+    /// `null` elsewhere or when it isn't one.
+    pub war: Option<WeaveVMArchiverBlock>,
+    /// How the archived calldata differs from the RPC's, `null` unless both
+    /// are available and differ.
+    pub mismatch: Option<CalldataDiff>,
+}
+
 impl GetBlockFromTx {
-    pub fn new(number: U256, hash: String, calldata: String) -> Self {
+    pub fn new(number: U256, hash: Option<B256>, calldata: Bytes) -> Self {
         GetBlockFromTx {
             number,
             hash,
@@ -64,13 +142,20 @@ impl HandlerGetCalldata {
 }
 
 impl EncodingUtils {
+    /// Decompresses `input` within the configured `DecodeLimits`. Crossing a
+    /// limit is an error wrapping a `LimitError`; any other decompression
+    /// failure still yields an empty buffer.
+    #[tracing::instrument(skip_all, fields(input_len = input.len()))]
     pub fn brotli_decompress(input: Vec<u8>) -> Result<Vec<u8>, Error> {
         let mut decompressed_data = Vec::new();
-        let mut decompressor = brotli::Decompressor::new(input.as_slice(), 4096); // 4096 is the buffer size
+        let mut decompressor = limited_brotli_reader(input.as_slice(), DecodeLimits::from_env());
 
         match decompressor.read_to_end(&mut decompressed_data) {
             Ok(_) => Ok(decompressed_data),
-            Err(_) => Ok(Vec::new()),
+            Err(e) => match LimitError::from_io(&e) {
+                Some(limit) => Err(Error::new(limit)),
+                None => Ok(Vec::new()),
+            },
         }
     }
 
@@ -79,86 +164,238 @@ impl EncodingUtils {
         Ok(from_borsh)
     }
 
-    pub fn wvm_archiver_borsh_deserialize(input: Vec<u8>) -> WeaveVMArchiverBlock {
-        let from_borsh: WeaveVMArchiverBlock = borsh::from_slice(&input).unwrap();
-        from_borsh
+    /// Decodes a block from any reader, e.g. a brotli decompressor fed by the
+    /// gateway stream, consuming only as many bytes as the block needs.
+    #[tracing::instrument(skip_all)]
+    pub fn borsh_deserialize_reader<R: Read>(
+        mut reader: R,
+    ) -> Result<BorshSealedBlockWithSenders, anyhow::Error> {
+        let from_borsh = BorshSealedBlockWithSenders::deserialize_reader(&mut reader)?;
+        Ok(from_borsh)
+    }
+
+    pub fn sealed_block_with_senders(
+        value: BorshSealedBlockWithSenders,
+    ) -> Result<SealedBlockWithSenders, UnsupportedBlockVersion> {
+        match value.0 {
+            WvmSealedBlockWithSenders::V1(data) => Ok(data.into()),
+            _ => Err(UnsupportedBlockVersion),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(input_len = input.len()))]
+    pub fn try_wvm_archiver_borsh_deserialize(
+        input: Vec<u8>,
+    ) -> Result<WeaveVMArchiverBlock, anyhow::Error> {
+        let from_borsh: WeaveVMArchiverBlock = borsh::from_slice(&input)?;
+        Ok(from_borsh)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, BorshSerialize, PartialEq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Block {
-    pub base_fee_per_gas: Option<String>,         // "baseFeePerGas"
-    pub blob_gas_used: Option<String>,            // "blobGasUsed"
-    pub difficulty: Option<String>,               // "difficulty"
-    pub excess_blob_gas: Option<String>,          // "excessBlobGas"
-    pub extra_data: Option<String>,               // "extraData"
-    pub gas_limit: Option<String>,                // "gasLimit"
-    pub gas_used: Option<String>,                 // "gasUsed"
-    pub hash: Option<String>,                     // "hash"
-    pub logs_bloom: Option<String>,               // "logsBloom"
-    pub miner: Option<String>,                    // "miner"
-    pub mix_hash: Option<String>,                 // "mixHash"
-    pub nonce: Option<String>,                    // "nonce"
-    pub number: Option<String>,                   // "number"
-    pub parent_beacon_block_root: Option<String>, // "parentBeaconBlockRoot"
-    pub parent_hash: Option<String>,              // "parentHash"
-    pub receipts_root: Option<String>,            // "receiptsRoot"
-    pub seal_fields: Vec<String>,                 // "sealFields" as an array of strings
-    pub sha3_uncles: Option<String>,              // "sha3Uncles"
-    pub size: Option<String>,                     // "size"
-    pub state_root: Option<String>,               // "stateRoot"
-    pub timestamp: Option<String>,                // "timestamp"
-    pub total_difficulty: Option<String>,         // "totalDifficulty"
-    pub transactions_and_calldata: Vec<(String, String)>, // "transactions_and_calldata" as an array of (string, string)
-}
-
-impl From<BorshSealedBlockWithSenders> for Block {
-    fn from(value: BorshSealedBlockWithSenders) -> Self {
-        let sealed_block = value.0;
-
-        match sealed_block {
-            WvmSealedBlockWithSenders::V1(data) => {
-                let data: SealedBlockWithSenders = data.into();
-                let sealed_block = data.block;
-                Block {
-                    base_fee_per_gas: sealed_block.base_fee_per_gas.map(|i| i.to_string()),
-                    blob_gas_used: sealed_block.blob_gas_used.map(|i| i.to_string()),
-                    difficulty: Some(sealed_block.difficulty.to_string()),
-                    excess_blob_gas: sealed_block.excess_blob_gas.map(|i| i.to_string()),
-                    extra_data: Some(sealed_block.extra_data.to_string()),
-                    gas_limit: Some(sealed_block.gas_limit.to_string()),
-                    gas_used: Some(sealed_block.gas_used.to_string()),
-                    hash: Some(sealed_block.hash().to_string()),
-                    logs_bloom: Some(sealed_block.logs_bloom.to_string()),
-                    miner: None,
-                    mix_hash: Some(sealed_block.mix_hash.to_string()),
-                    nonce: Some(sealed_block.nonce.to_string()),
-                    number: Some(sealed_block.number.to_string()),
-                    parent_beacon_block_root: sealed_block
-                        .parent_beacon_block_root
-                        .map(|i| i.to_string()),
-                    parent_hash: Some(sealed_block.parent_hash.to_string()),
-                    receipts_root: Some(sealed_block.receipts_root.to_string()),
-                    seal_fields: vec![],
-                    sha3_uncles: None,
-                    size: Some(sealed_block.size().to_string()),
-                    state_root: Some(sealed_block.state_root.to_string()),
-                    timestamp: Some(sealed_block.timestamp.to_string()),
-                    total_difficulty: None,
-                    transactions_and_calldata: sealed_block
-                        .body
-                        .transactions()
-                        .map(|i| (i.hash.to_string(), i.transaction.input().to_string()))
-                        .collect::<Vec<(String, String)>>(),
-                }
-            }
-            _ => unimplemented!(),
+    pub base_fee_per_gas: Option<String>, // "baseFeePerGas"
+    pub blob_gas_used: Option<String>,    // "blobGasUsed"
+    pub difficulty: Option<String>,       // "difficulty"
+    pub excess_blob_gas: Option<String>,  // "excessBlobGas"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_bytes")]
+    pub extra_data: Option<Bytes>, // "extraData"
+    pub gas_limit: Option<String>,        // "gasLimit"
+    pub gas_used: Option<String>,         // "gasUsed"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub hash: Option<B256>, // "hash"
+    pub logs_bloom: Option<String>,       // "logsBloom"
+    pub miner: Option<String>,            // "miner", the header's beneficiary
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub mix_hash: Option<B256>, // "mixHash"
+    pub nonce: Option<String>,            // "nonce", decimal like the other quantities
+    pub number: Option<String>,           // "number"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub parent_beacon_block_root: Option<B256>, // "parentBeaconBlockRoot"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub parent_hash: Option<B256>, // "parentHash"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub receipts_root: Option<B256>, // "receiptsRoot"
+    pub seal_fields: Vec<String>,         // "sealFields" as an array of strings
+    pub sha3_uncles: Option<String>,      // "sha3Uncles", the header's ommers hash
+    pub size: Option<String>,             // "size"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub state_root: Option<B256>, // "stateRoot"
+    pub timestamp: Option<String>,        // "timestamp"
+    pub total_difficulty: Option<String>, // "totalDifficulty", not part of an archived header
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub transactions_root: Option<B256>, // "transactionsRoot"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_b256")]
+    pub withdrawals_root: Option<B256>, // "withdrawalsRoot", none before Shanghai
+    pub withdrawals: Option<Vec<ArchivedWithdrawal>>, // "withdrawals", none before Shanghai
+    #[schemars(with = "Vec<(String, String)>")]
+    #[borsh(serialize_with = "borsh_bytes::hashes_and_bytes")]
+    pub transactions_and_calldata: Vec<(B256, Bytes)>, // "transactions_and_calldata" as an array of (hash, calldata)
+    pub transactions: Vec<ArchivedTransaction>, // "transactions" in block order
+}
+
+#[derive(Debug, Deserialize, Serialize, BorshSerialize, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedWithdrawal {
+    pub index: String,           // "index"
+    pub validator_index: String, // "validatorIndex"
+    #[schemars(with = "String")]
+    #[borsh(serialize_with = "borsh_bytes::address")]
+    pub address: Address, // "address"
+    pub amount: String, // "amount", in gwei
+}
+
+#[derive(Debug, Deserialize, Serialize, BorshSerialize, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedAccessListItem {
+    #[schemars(with = "String")]
+    #[borsh(serialize_with = "borsh_bytes::address")]
+    pub address: Address, // "address"
+    #[schemars(with = "Vec<String>")]
+    #[borsh(serialize_with = "borsh_bytes::b256s")]
+    pub storage_keys: Vec<B256>, // "storageKeys"
+}
+
+#[derive(Debug, Deserialize, Serialize, BorshSerialize, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedTransaction {
+    #[schemars(with = "String")]
+    #[borsh(serialize_with = "borsh_bytes::b256")]
+    pub hash: B256, // "hash"
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_address")]
+    pub from: Option<Address>, // "from", recovered sender as archived with the block
+    #[schemars(with = "Option<String>")]
+    #[borsh(serialize_with = "borsh_bytes::opt_address")]
+    pub to: Option<Address>, // "to", none for contract creations
+    pub nonce: String, // "nonce"
+    pub value: String, // "value"
+    pub gas: String,   // "gas"
+    #[schemars(with = "String")]
+    #[borsh(serialize_with = "borsh_bytes::bytes")]
+    pub input: Bytes, // "input"
+    pub tx_type: String, // "txType"
+    pub chain_id: Option<String>, // "chainId"
+    pub gas_price: Option<String>, // "gasPrice", effective at the block's base fee
+    pub max_fee_per_gas: Option<String>, // "maxFeePerGas", EIP-1559 txs only
+    pub max_priority_fee_per_gas: Option<String>, // "maxPriorityFeePerGas", EIP-1559 txs only
+    pub access_list: Option<Vec<ArchivedAccessListItem>>, // "accessList", typed txs only
+    pub r: String,       // "r"
+    pub s: String,       // "s"
+    pub y_parity: bool,  // "yParity"
+}
+
+impl TryFrom<BorshSealedBlockWithSenders> for Block {
+    type Error = UnsupportedBlockVersion;
+
+    fn try_from(value: BorshSealedBlockWithSenders) -> Result<Self, Self::Error> {
+        Ok(Block::from(&EncodingUtils::sealed_block_with_senders(value)?))
+    }
+}
+
+impl From<&SealedBlockWithSenders> for Block {
+    #[tracing::instrument(name = "block_from_sealed_block", skip_all)]
+    fn from(data: &SealedBlockWithSenders) -> Self {
+        let senders = &data.senders;
+        let sealed_block = &data.block;
+        Block {
+            base_fee_per_gas: sealed_block.base_fee_per_gas.map(|i| i.to_string()),
+            blob_gas_used: sealed_block.blob_gas_used.map(|i| i.to_string()),
+            difficulty: Some(sealed_block.difficulty.to_string()),
+            excess_blob_gas: sealed_block.excess_blob_gas.map(|i| i.to_string()),
+            extra_data: Some(sealed_block.extra_data.clone()),
+            gas_limit: Some(sealed_block.gas_limit.to_string()),
+            gas_used: Some(sealed_block.gas_used.to_string()),
+            hash: Some(sealed_block.hash()),
+            logs_bloom: Some(sealed_block.logs_bloom.to_string()),
+            miner: Some(format!("{:#x}", sealed_block.beneficiary)),
+            mix_hash: Some(sealed_block.mix_hash),
+            nonce: Some(u64::from(sealed_block.nonce).to_string()),
+            number: Some(sealed_block.number.to_string()),
+            parent_beacon_block_root: sealed_block.parent_beacon_block_root,
+            parent_hash: Some(sealed_block.parent_hash),
+            receipts_root: Some(sealed_block.receipts_root),
+            seal_fields: vec![],
+            sha3_uncles: Some(format!("{:#x}", sealed_block.ommers_hash)),
+            size: Some(sealed_block.size().to_string()),
+            state_root: Some(sealed_block.state_root),
+            timestamp: Some(sealed_block.timestamp.to_string()),
+            total_difficulty: None,
+            transactions_root: Some(sealed_block.transactions_root),
+            withdrawals_root: sealed_block.withdrawals_root,
+            withdrawals: sealed_block.body.withdrawals.as_ref().map(|withdrawals| {
+                withdrawals
+                    .iter()
+                    .map(|withdrawal| ArchivedWithdrawal {
+                        index: withdrawal.index.to_string(),
+                        validator_index: withdrawal.validator_index.to_string(),
+                        address: withdrawal.address,
+                        amount: withdrawal.amount.to_string(),
+                    })
+                    .collect()
+            }),
+            transactions_and_calldata: sealed_block
+                .body
+                .transactions()
+                .map(|i| (i.hash, i.transaction.input().clone()))
+                .collect::<Vec<(B256, Bytes)>>(),
+            transactions: sealed_block
+                .body
+                .transactions()
+                .enumerate()
+                .map(|(index, i)| ArchivedTransaction {
+                    hash: i.hash,
+                    from: senders.get(index).copied(),
+                    to: i.transaction.to(),
+                    nonce: i.transaction.nonce().to_string(),
+                    value: i.transaction.value().to_string(),
+                    gas: i.transaction.gas_limit().to_string(),
+                    input: i.transaction.input().clone(),
+                    tx_type: u8::from(i.transaction.tx_type()).to_string(),
+                    chain_id: i.transaction.chain_id().map(|id| id.to_string()),
+                    gas_price: Some(
+                        i.transaction
+                            .effective_gas_price(sealed_block.base_fee_per_gas)
+                            .to_string(),
+                    ),
+                    max_fee_per_gas: i
+                        .transaction
+                        .max_priority_fee_per_gas()
+                        .map(|_| i.transaction.max_fee_per_gas().to_string()),
+                    max_priority_fee_per_gas: i
+                        .transaction
+                        .max_priority_fee_per_gas()
+                        .map(|fee| fee.to_string()),
+                    access_list: i.transaction.access_list().map(|list| {
+                        list.iter()
+                            .map(|item| ArchivedAccessListItem {
+                                address: item.address,
+                                storage_keys: item.storage_keys.clone(),
+                            })
+                            .collect()
+                    }),
+                    r: i.signature.r().to_string(),
+                    s: i.signature.s().to_string(),
+                    y_parity: i.signature.v().y_parity(),
+                })
+                .collect::<Vec<ArchivedTransaction>>(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, BorshSerialize, BorshDeserialize, PartialEq)]
+#[derive(
+    Debug, Deserialize, Serialize, BorshSerialize, BorshDeserialize, PartialEq, JsonSchema,
+)]
 #[serde(rename_all = "camelCase")]
 pub struct WeaveVMArchiverBlock {
     pub base_fee_per_gas: Option<String>,         // "baseFeePerGas"
@@ -185,3 +422,56 @@ pub struct WeaveVMArchiverBlock {
     pub total_difficulty: Option<String>,         // "totalDifficulty"
     pub transactions: Vec<String>,                // "transactions" as an array of strings
 }
+
+/// Borsh encodings for the alloy byte types, which don't implement borsh:
+/// hashes and addresses as fixed-size arrays, byte strings length-prefixed.
+pub(crate) mod borsh_bytes {
+    use borsh::io::{Result, Write};
+    use borsh::BorshSerialize;
+    use reth_primitives::{Address, Bytes, B256};
+
+    pub fn b256<W: Write>(value: &B256, writer: &mut W) -> Result<()> {
+        value.0.serialize(writer)
+    }
+
+    pub fn opt_b256<W: Write>(value: &Option<B256>, writer: &mut W) -> Result<()> {
+        value.map(|value| value.0).serialize(writer)
+    }
+
+    pub fn bytes<W: Write>(value: &Bytes, writer: &mut W) -> Result<()> {
+        value.as_ref().serialize(writer)
+    }
+
+    pub fn opt_bytes<W: Write>(value: &Option<Bytes>, writer: &mut W) -> Result<()> {
+        value.as_ref().map(|value| value.as_ref()).serialize(writer)
+    }
+
+    pub fn b256s<W: Write>(value: &[B256], writer: &mut W) -> Result<()> {
+        u32::try_from(value.len())
+            .map_err(|_| borsh::io::Error::other("too many entries"))?
+            .serialize(writer)?;
+        for hash in value {
+            hash.0.serialize(writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn address<W: Write>(value: &Address, writer: &mut W) -> Result<()> {
+        value.0 .0.serialize(writer)
+    }
+
+    pub fn opt_address<W: Write>(value: &Option<Address>, writer: &mut W) -> Result<()> {
+        value.map(|value| value.0 .0).serialize(writer)
+    }
+
+    pub fn hashes_and_bytes<W: Write>(value: &[(B256, Bytes)], writer: &mut W) -> Result<()> {
+        u32::try_from(value.len())
+            .map_err(|_| borsh::io::Error::other("too many entries"))?
+            .serialize(writer)?;
+        for (hash, bytes) in value {
+            hash.0.serialize(writer)?;
+            bytes.as_ref().serialize(writer)?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,571 @@
-use crate::utils::getters::{get_calldata, get_calldata_by_tag, get_war_calldata};
-use axum::{extract::Path, response::Json};
-use serde_json::Value;
+use crate::utils::access::{authorized_usage, is_admin};
+use crate::utils::archive_rpc::{handle_rpc_payload, load_archived_block};
+use crate::utils::arweave::get_archive_bytes;
+use crate::utils::cache::{cache_stats, get_cached_archive_id};
+use crate::utils::formats::{encode, negotiate, Format, FormatQuery};
+use crate::utils::getters::{resolve_calldata, resolve_calldata_v2};
+use crate::utils::graphql::{execute_graphql, graphiql_source};
+use crate::utils::health::readiness;
+use crate::utils::http_cache::{cached_json_response, cached_response};
+use crate::utils::incidents::{
+    acknowledge_incident, list_incidents, Acknowledgement, IncidentQuery,
+};
+use crate::utils::index::get_archive_id;
+use crate::utils::metrics;
+use crate::utils::openapi;
+use crate::utils::pipeline::{RetrievalError, TxSelector};
+use crate::utils::policy::{policy_from_query, PolicyError, PolicyQuery, SourcePolicy};
+use crate::utils::provenance::{
+    debug_requested, with_provenance, DebugQuery, Provenance, DEBUG_HEADER, PROVENANCE_HEADER,
+};
+use crate::utils::upstream::{upstream_config, UpstreamBusy, UpstreamUnavailable};
+use anyhow::Error;
+use axum::{
+    extract::{Path, Query},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Json, Response},
+};
+use borsh::BorshSerialize;
+use serde::Serialize;
+use serde_json::{json, Value};
 
 pub async fn handle_weave_gm() -> &'static str {
     "WeaveGM!"
 }
 
-pub async fn handle_get_calldata(Path(txid): Path<String>) -> Json<Value> {
-    get_calldata(txid).await
+pub async fn handle_get_calldata(
+    Path(txid): Path<String>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let options = (query, policy, debug);
+    calldata_v1(
+        TxSelector::TxId(txid.clone()),
+        false,
+        &txid,
+        options,
+        headers,
+    )
+    .await
 }
 
-pub async fn handle_get_war_calldata(Path(txid): Path<String>) -> Json<Value> {
-    get_war_calldata(txid).await
+pub async fn handle_get_war_calldata(
+    Path(txid): Path<String>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let options = (query, policy, debug);
+    calldata_v1(
+        TxSelector::TxId(txid.clone()),
+        true,
+        &txid,
+        options,
+        headers,
+    )
+    .await
 }
 
-pub async fn handle_get_calldata_by_tag(Path((tag1, tag2)): Path<(String, String)>) -> Json<Value> {
-    get_calldata_by_tag([tag1, tag2]).await
+pub async fn handle_get_calldata_by_tag(
+    Path((tag1, tag2)): Path<(String, String)>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let tag_id = format!("{}/{}", tag1, tag2);
+    let options = (query, policy, debug);
+    calldata_v1(
+        TxSelector::Tag([tag1, tag2]),
+        false,
+        &tag_id,
+        options,
+        headers,
+    )
+    .await
+}
+
+/// `?format=`, `?policy=` and `?debug=` of the calldata routes.
+type CalldataOptions = (FormatQuery, PolicyQuery, DebugQuery);
+
+/// Parsed `CalldataOptions`, or the rejection of the first invalid one.
+fn parse_options(
+    headers: &HeaderMap,
+    (query, policy, debug): &CalldataOptions,
+) -> Result<(Format, SourcePolicy, bool), (StatusCode, String)> {
+    let format = negotiate(headers, query)?;
+    let policy = policy_from_query(policy)?;
+    Ok((format, policy, debug_requested(headers, debug)))
+}
+
+async fn calldata_v1(
+    selector: TxSelector,
+    war: bool,
+    resource_id: &str,
+    options: CalldataOptions,
+    headers: HeaderMap,
+) -> Response {
+    let (format, policy, debug) = match parse_options(&headers, &options) {
+        Ok(options) => options,
+        Err(rejection) => return rejection.into_response(),
+    };
+    // a tag can be reused by a later tx, so tag lookups are never immutable
+    let allow_immutable = matches!(selector, TxSelector::TxId(_));
+    let (res, provenance) = with_provenance(resolve_calldata(selector, war, policy)).await;
+    let provenance = debug.then_some(&provenance);
+    match res {
+        Ok(res) => negotiated_calldata_response(
+            &headers,
+            resource_id,
+            &res,
+            &res.calldata,
+            res.da_archive_is_equal_data,
+            format,
+            allow_immutable,
+            provenance,
+        ),
+        Err(e) => failure_response(&e, provenance),
+    }
+}
+
+/// 503 with a `Retry-After` when an upstream rejected the retrieval: the
+/// breaker's open period for an open circuit, a short wait for a full queue.
+/// 400 for a malformed txid, 404 for an unknown tx or tag and when the
+/// `?policy=` needs a source without the calldata, 409 when the sources
+/// disagree, with the diff for `require_match`. 500 for anything else.
+fn retrieval_failure(err: &Error) -> Response {
+    match RetrievalError::find(err) {
+        Some(RetrievalError::InvalidTxid(_)) => {
+            return (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+        Some(RetrievalError::NotFound(_)) => {
+            return (StatusCode::NOT_FOUND, err.to_string()).into_response()
+        }
+        None => {}
+    }
+    match PolicyError::find(err) {
+        Some(PolicyError::Missing { .. }) => {
+            return (StatusCode::NOT_FOUND, err.to_string()).into_response()
+        }
+        Some(PolicyError::Mismatch(diff)) => {
+            let body = json!({ "error": err.to_string(), "diff": diff });
+            return (StatusCode::CONFLICT, Json(body)).into_response();
+        }
+        Some(PolicyError::NoQuorum { .. }) => {
+            return (StatusCode::CONFLICT, err.to_string()).into_response()
+        }
+        None => {}
+    }
+    let config = upstream_config();
+    let retry_after = if UpstreamUnavailable::find(err).is_some() {
+        config.breaker_open_for
+    } else if UpstreamBusy::find(err).is_some() {
+        config.busy_retry_after
+    } else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    };
+    let mut res = (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response();
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs().max(1)));
+    res
+}
+
+/// `retrieval_failure`, with the provenance of a debug request.
+fn failure_response(err: &Error, provenance: Option<&Provenance>) -> Response {
+    let mut res = with_provenance_header(retrieval_failure(err), provenance);
+    if provenance.is_some() {
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    vary_on_debug(res)
+}
+
+/// Calldata responses differ between debug and plain requests, so shared
+/// caches must not serve one for the other.
+fn vary_on_debug(mut res: Response) -> Response {
+    let vary = match res.headers().get(VARY).and_then(|vary| vary.to_str().ok()) {
+        Some(vary) => format!("{}, {}", vary, DEBUG_HEADER),
+        None => DEBUG_HEADER.to_string(),
+    };
+    res.headers_mut()
+        .insert(VARY, HeaderValue::from_str(&vary).unwrap());
+    res
+}
+
+/// `verified` results (archive equal to the DA calldata) may be immutable.
+/// With a `provenance` the response is a debug one, see `debug_response`.
+/// Either way it varies on the debug header.
+#[allow(clippy::too_many_arguments)]
+fn negotiated_calldata_response<T: Serialize + BorshSerialize>(
+    headers: &HeaderMap,
+    resource_id: &str,
+    res: &T,
+    calldata: &[u8],
+    verified: bool,
+    format: Format,
+    allow_immutable: bool,
+    provenance: Option<&Provenance>,
+) -> Response {
+    let res = match provenance {
+        Some(provenance) => debug_response(res, calldata, format, provenance),
+        None if format == Format::Json => {
+            let body = serde_json::to_value(res).unwrap();
+            cached_json_response(headers, resource_id, body, allow_immutable)
+        }
+        None => {
+            let body = match format {
+                Format::Raw => calldata.to_vec(),
+                format => encode(res, format).unwrap(),
+            };
+            cached_response(
+                headers,
+                resource_id,
+                body,
+                format.content_type(),
+                calldata,
+                allow_immutable && verified,
+            )
+        }
+    };
+    vary_on_debug(res)
+}
+
+/// The provenance of a debug request: a `provenance` field in JSON bodies,
+/// the `x-wvm-provenance` header for the other formats. It differs between
+/// requests, so debug responses are neither cached nor given an `ETag`.
+fn debug_response<T: Serialize + BorshSerialize>(
+    res: &T,
+    calldata: &[u8],
+    format: Format,
+    provenance: &Provenance,
+) -> Response {
+    let body = match format {
+        Format::Json => {
+            let mut body = serde_json::to_value(res).unwrap();
+            body["provenance"] = serde_json::to_value(provenance).unwrap();
+            serde_json::to_vec(&body).unwrap()
+        }
+        Format::Raw => calldata.to_vec(),
+        format => encode(res, format).unwrap(),
+    };
+    let mut res = ([(CONTENT_TYPE, format.content_type())], body).into_response();
+    if format != Format::Json {
+        res = with_provenance_header(res, Some(provenance));
+    }
+    res.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res.headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    res
+}
+
+fn with_provenance_header(mut res: Response, provenance: Option<&Provenance>) -> Response {
+    let value = provenance.and_then(|provenance| {
+        HeaderValue::from_str(&serde_json::to_string(provenance).ok()?).ok()
+    });
+    if let Some(value) = value {
+        res.headers_mut().insert(PROVENANCE_HEADER, value);
+    }
+    res
+}
+
+pub async fn handle_get_calldata_v2(
+    Path(txid): Path<String>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let options = (query, policy, debug);
+    calldata_v2(
+        TxSelector::TxId(txid.clone()),
+        false,
+        &txid,
+        options,
+        headers,
+    )
+    .await
+}
+
+pub async fn handle_get_war_calldata_v2(
+    Path(txid): Path<String>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let options = (query, policy, debug);
+    calldata_v2(
+        TxSelector::TxId(txid.clone()),
+        true,
+        &txid,
+        options,
+        headers,
+    )
+    .await
+}
+
+pub async fn handle_get_calldata_by_tag_v2(
+    Path((tag1, tag2)): Path<(String, String)>,
+    Query(query): Query<FormatQuery>,
+    Query(policy): Query<PolicyQuery>,
+    Query(debug): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let tag_id = format!("{}/{}", tag1, tag2);
+    let options = (query, policy, debug);
+    calldata_v2(
+        TxSelector::Tag([tag1, tag2]),
+        false,
+        &tag_id,
+        options,
+        headers,
+    )
+    .await
+}
+
+async fn calldata_v2(
+    selector: TxSelector,
+    war: bool,
+    resource_id: &str,
+    options: CalldataOptions,
+    headers: HeaderMap,
+) -> Response {
+    let (format, policy, debug) = match parse_options(&headers, &options) {
+        Ok(options) => options,
+        Err(rejection) => return rejection.into_response(),
+    };
+    // a tag can be reused by a later tx, so tag lookups are never immutable
+    let allow_immutable = matches!(selector, TxSelector::TxId(_));
+    let (res, provenance) = with_provenance(resolve_calldata_v2(selector, war, policy)).await;
+    let provenance = debug.then_some(&provenance);
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => return failure_response(&e, provenance),
+    };
+    let calldata = res.calldata.clone().unwrap_or_default();
+    negotiated_calldata_response(
+        &headers,
+        &format!("v2/{}", resource_id),
+        &res,
+        &calldata,
+        res.da_archive_is_equal_data == Some(true),
+        format,
+        allow_immutable,
+        provenance,
+    )
+}
+
+/// Archived WeaveVM block, in any negotiated format. `raw` serves the
+/// brotli-compressed archive exactly as stored on Arweave.
+pub async fn handle_get_block(
+    Path(number): Path<u64>,
+    Query(query): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let format = match negotiate(&headers, &query) {
+        Ok(format) => format,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let block = match load_archived_block(number).await {
+        Ok(Some(block)) => block,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.message).into_response(),
+    };
+    let body = match format {
+        Format::Raw => {
+            let arweave_id = match get_cached_archive_id(number) {
+                Some(arweave_id) => Some(arweave_id),
+                None => get_archive_id(number).await,
+            };
+            let Some(arweave_id) = arweave_id else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let archive = get_archive_bytes(&arweave_id).await;
+            if archive.is_empty() {
+                return StatusCode::BAD_GATEWAY.into_response();
+            }
+            archive.to_vec()
+        }
+        format => encode(block.as_ref(), format).unwrap(),
+    };
+    // archives never change once written
+    cached_response(
+        &headers,
+        &format!("block/{}", number),
+        body,
+        format.content_type(),
+        &[],
+        true,
+    )
+}
+
+pub async fn handle_graphql(
+    Json(req): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(execute_graphql(req).await)
+}
+
+pub async fn handle_graphiql() -> Html<String> {
+    Html(graphiql_source())
+}
+
+pub async fn handle_archive_rpc(Json(payload): Json<Value>) -> Json<Value> {
+    Json(handle_rpc_payload(payload).await)
+}
+
+pub async fn handle_cache_stats() -> Json<Value> {
+    Json(serde_json::to_value(cache_stats()).unwrap())
+}
+
+pub async fn handle_health_live() -> Json<Value> {
+    Json(json!({ "status": "live" }))
+}
+
+/// 200 when every dependency check passes, 503 otherwise, with the result of
+/// each check in the body.
+pub async fn handle_health_ready() -> Response {
+    let readiness = readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(serde_json::to_value(&*readiness).unwrap())).into_response()
+}
+
+/// Usage ledger of the calling API key.
+pub async fn handle_usage(headers: HeaderMap) -> Response {
+    authorized_usage(&headers, None)
+}
+
+/// Usage ledger of any API key, for the admin token.
+pub async fn handle_usage_of_key(Path(key_id): Path<String>, headers: HeaderMap) -> Response {
+    authorized_usage(&headers, Some(&key_id))
+}
+
+/// Logged archive/DA discrepancies, for the admin token.
+pub async fn handle_incidents(headers: HeaderMap, Query(query): Query<IncidentQuery>) -> Response {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "admin token required").into_response();
+    }
+    match list_incidents(&query) {
+        Ok(incidents) => Json(incidents).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Marks an incident as followed up, with an optional `{"note": ...}` body.
+pub async fn handle_acknowledge_incident(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    ack: Option<Json<Acknowledgement>>,
+) -> Response {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "admin token required").into_response();
+    }
+    let ack = ack.map(|Json(ack)| ack).unwrap_or_default();
+    match acknowledge_incident(id, &ack) {
+        Ok(Some(incident)) => Json(incident).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("no incident {}", id)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub async fn handle_openapi() -> Json<Value> {
+    Json(openapi::document().clone())
+}
+
+pub async fn handle_docs() -> Html<&'static str> {
+    Html(openapi::redoc_page())
+}
+
+pub async fn handle_metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::ETAG;
+
+    #[test]
+    fn test_retrieval_failure_status() {
+        let status = |err: Error| retrieval_failure(&err).status();
+        assert_eq!(
+            status(Error::new(RetrievalError::InvalidTxid("0x12".to_string()))),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Error::new(RetrievalError::NotFound(
+                "transaction 0x12".to_string()
+            ))),
+            StatusCode::NOT_FOUND
+        );
+        let unavailable = Error::new(UpstreamUnavailable {
+            upstream: "wvm-rpc".to_string(),
+        });
+        let res = retrieval_failure(&unavailable.context("tx lookup"));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().contains_key(RETRY_AFTER));
+        assert_eq!(
+            status(Error::msg("decode failed")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn test_debug_responses_are_kept_apart() {
+        #[derive(Serialize, borsh_derive::BorshSerialize)]
+        struct Res {
+            calldata: String,
+            da_archive_is_equal_data: bool,
+        }
+        let res = Res {
+            calldata: "0xdead".to_string(),
+            da_archive_is_equal_data: true,
+        };
+        let txid = "0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a";
+        let respond = |format, provenance| {
+            negotiated_calldata_response(
+                &HeaderMap::new(),
+                txid,
+                &res,
+                &[0xde, 0xad],
+                true,
+                format,
+                true,
+                provenance,
+            )
+        };
+        let provenance = Provenance::default();
+
+        for format in [Format::Json, Format::Cbor] {
+            let plain = respond(format, None);
+            assert!(plain.headers().contains_key(ETAG));
+            assert_eq!(plain.headers()[VARY], "accept, x-wvm-debug");
+
+            let debug = respond(format, Some(&provenance));
+            assert!(!debug.headers().contains_key(ETAG));
+            assert_eq!(debug.headers()[CACHE_CONTROL], "no-store");
+            assert_eq!(debug.headers()[VARY], "accept, x-wvm-debug");
+        }
+
+        let failure = failure_response(&Error::msg("decode failed"), Some(&provenance));
+        assert_eq!(failure.headers()[CACHE_CONTROL], "no-store");
+        assert!(failure.headers().contains_key(PROVENANCE_HEADER));
+        assert_eq!(
+            failure_response(&Error::msg("decode failed"), None).headers()[VARY],
+            "x-wvm-debug"
+        );
+    }
 }
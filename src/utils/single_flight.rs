@@ -0,0 +1,248 @@
+use crate::utils::limits::LimitError;
+use crate::utils::upstream::{UpstreamBusy, UpstreamUnavailable};
+use anyhow::Error;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::Instrument;
+
+type Flight<V> = Shared<BoxFuture<'static, V>>;
+
+/// Deduplicates concurrent calls for the same key: the first caller starts
+/// the upstream call, everyone asking for that key while it is in flight
+/// awaits the same future and gets a clone of its output.
+///
+/// A flight every caller gave up on is forgotten, so the next caller starts
+/// over instead of joining a future nobody polls.
+pub struct SingleFlight<K, V: Clone> {
+    calls: Mutex<HashMap<K, InFlight<V>>>,
+    next_id: AtomicU64,
+}
+
+struct InFlight<V: Clone> {
+    id: u64,
+    waiters: usize,
+    flight: Flight<V>,
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        SingleFlight {
+            calls: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    pub async fn run<F, Fut>(&self, key: K, call: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V> + Send + 'static,
+    {
+        let (id, flight) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get_mut(&key) {
+                Some(in_flight) => {
+                    in_flight.waiters += 1;
+                    (in_flight.id, in_flight.flight.clone())
+                }
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    // the flight runs in the span of the caller that started it
+                    let flight = call().in_current_span().boxed().shared();
+                    calls.insert(
+                        key.clone(),
+                        InFlight {
+                            id,
+                            waiters: 1,
+                            flight: flight.clone(),
+                        },
+                    );
+                    (id, flight)
+                }
+            }
+        };
+        let waiter = Waiter {
+            calls: &self.calls,
+            key,
+            id,
+        };
+
+        let value = flight.await;
+
+        // settled: later callers start a new flight, even while other
+        // waiters of this one are still picking up the value
+        waiter.forget_flight();
+        value
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.calls.lock().unwrap().len()
+    }
+}
+
+/// One caller awaiting a flight. Dropping the last one before the flight
+/// settles, e.g. because every caller timed out, forgets the flight.
+struct Waiter<'a, K: Eq + Hash, V: Clone> {
+    calls: &'a Mutex<HashMap<K, InFlight<V>>>,
+    key: K,
+    id: u64,
+}
+
+impl<K: Eq + Hash, V: Clone> Waiter<'_, K, V> {
+    fn forget_flight(&self) {
+        let mut calls = self.calls.lock().unwrap();
+        // only forget our own flight, a newer one may already have replaced it
+        if calls
+            .get(&self.key)
+            .is_some_and(|in_flight| in_flight.id == self.id)
+        {
+            calls.remove(&self.key);
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Drop for Waiter<'_, K, V> {
+    fn drop(&mut self) {
+        let mut calls = self.calls.lock().unwrap();
+        if let Some(in_flight) = calls.get_mut(&self.key) {
+            if in_flight.id == self.id {
+                in_flight.waiters -= 1;
+                if in_flight.waiters == 0 {
+                    calls.remove(&self.key);
+                }
+            }
+        }
+    }
+}
+
+/// Flight outputs must be `Clone`, so errors are shared as their message,
+/// except decode limits and upstream rejections which callers may want to
+/// match on.
+#[derive(Debug, Clone)]
+pub enum FlightError {
+    Limit(LimitError),
+    Unavailable(UpstreamUnavailable),
+    Busy(UpstreamBusy),
+    Other(String),
+}
+
+impl From<Error> for FlightError {
+    fn from(err: Error) -> Self {
+        if let Some(limit) = LimitError::find(&err) {
+            return FlightError::Limit(limit);
+        }
+        if let Some(unavailable) = UpstreamUnavailable::find(&err) {
+            return FlightError::Unavailable(unavailable.clone());
+        }
+        match UpstreamBusy::find(&err) {
+            Some(busy) => FlightError::Busy(busy.clone()),
+            None => FlightError::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<FlightError> for Error {
+    fn from(err: FlightError) -> Self {
+        match err {
+            FlightError::Limit(limit) => Error::new(limit),
+            FlightError::Unavailable(unavailable) => Error::new(unavailable),
+            FlightError::Busy(busy) => Error::new(busy),
+            FlightError::Other(message) => Error::msg(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_calls_share_one_flight() {
+        let flights: Arc<SingleFlight<String, u64>> = Arc::new(SingleFlight::default());
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+
+        let mut handles = Vec::new();
+        for _ in 0..50 {
+            let flights = flights.clone();
+            let upstream_calls = upstream_calls.clone();
+            handles.push(tokio::spawn(async move {
+                flights
+                    .run("block".to_string(), || async move {
+                        upstream_calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        42
+                    })
+                    .await
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), 42);
+        }
+
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(flights.in_flight(), 0);
+
+        // once settled, the next call goes upstream again
+        assert_eq!(flights.run("block".to_string(), || async { 7 }).await, 7);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_flight_is_forgotten() {
+        let flights: SingleFlight<String, u64> = SingleFlight::default();
+
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(10),
+            flights.run("block".to_string(), std::future::pending),
+        )
+        .await;
+        assert!(abandoned.is_err());
+        assert_eq!(flights.in_flight(), 0);
+
+        // the next caller doesn't join the flight nobody polls anymore
+        assert_eq!(flights.run("block".to_string(), || async { 7 }).await, 7);
+    }
+
+    #[tokio::test]
+    async fn test_flight_outlives_one_of_its_waiters() {
+        let flights: Arc<SingleFlight<String, u64>> = Arc::new(SingleFlight::default());
+
+        let waiting = {
+            let flights = flights.clone();
+            tokio::spawn(async move {
+                flights
+                    .run("block".to_string(), || async {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        42
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let impatient = tokio::time::timeout(
+            Duration::from_millis(10),
+            flights.run("block".to_string(), || async { 7 }),
+        )
+        .await;
+        assert!(impatient.is_err());
+        assert_eq!(flights.in_flight(), 1);
+
+        assert_eq!(waiting.await.unwrap(), 42);
+        assert_eq!(flights.in_flight(), 0);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::utils::config::env_or;
+use crate::utils::constants::{
+    BREAKER_FAILURE_THRESHOLD, BREAKER_OPEN_SECS, UPSTREAM_BUSY_RETRY_AFTER_SECS,
+    UPSTREAM_CONNECT_TIMEOUT_MS, UPSTREAM_MAX_CONCURRENCY, UPSTREAM_MAX_QUEUE,
+    UPSTREAM_MAX_RETRIES, UPSTREAM_READ_TIMEOUT_MS, UPSTREAM_RETRY_BASE_MS, UPSTREAM_RETRY_MAX_MS,
+};
+use crate::utils::metrics::{
+    observe_upstream, observe_upstream_rejected, set_circuit_open, set_upstream_queued,
+};
+use anyhow::Error;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Timeouts, retries and circuit breaking of WeaveVM RPC and gateway calls.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamConfig {
+    pub connect_timeout: Duration,
+    /// Longest wait for any single read, so streamed archives may take longer
+    /// as long as they keep making progress.
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+    /// Consecutive failures opening the circuit.
+    pub breaker_threshold: u32,
+    pub breaker_open_for: Duration,
+    /// Calls in flight per upstream.
+    pub max_concurrency: usize,
+    /// Calls waiting for a slot per upstream, beyond which they're rejected.
+    pub max_queue: usize,
+    pub busy_retry_after: Duration,
+}
+
+impl UpstreamConfig {
+    pub fn from_env() -> Self {
+        let millis = |key, default| Duration::from_millis(env_or(key, default));
+        UpstreamConfig {
+            connect_timeout: millis(
+                "WVM_UPSTREAM_CONNECT_TIMEOUT_MS",
+                UPSTREAM_CONNECT_TIMEOUT_MS,
+            ),
+            read_timeout: millis("WVM_UPSTREAM_READ_TIMEOUT_MS", UPSTREAM_READ_TIMEOUT_MS),
+            max_retries: env_or("WVM_UPSTREAM_MAX_RETRIES", UPSTREAM_MAX_RETRIES),
+            retry_base: millis("WVM_UPSTREAM_RETRY_BASE_MS", UPSTREAM_RETRY_BASE_MS),
+            retry_max: millis("WVM_UPSTREAM_RETRY_MAX_MS", UPSTREAM_RETRY_MAX_MS),
+            breaker_threshold: env_or("WVM_BREAKER_FAILURE_THRESHOLD", BREAKER_FAILURE_THRESHOLD),
+            breaker_open_for: Duration::from_secs(env_or(
+                "WVM_BREAKER_OPEN_SECS",
+                BREAKER_OPEN_SECS,
+            )),
+            max_concurrency: env_or("WVM_UPSTREAM_MAX_CONCURRENCY", UPSTREAM_MAX_CONCURRENCY)
+                .max(1),
+            max_queue: env_or("WVM_UPSTREAM_MAX_QUEUE", UPSTREAM_MAX_QUEUE),
+            busy_retry_after: Duration::from_secs(env_or(
+                "WVM_UPSTREAM_BUSY_RETRY_AFTER_SECS",
+                UPSTREAM_BUSY_RETRY_AFTER_SECS,
+            )),
+        }
+    }
+}
+
+static CONFIG: LazyLock<UpstreamConfig> = LazyLock::new(UpstreamConfig::from_env);
+
+pub fn upstream_config() -> UpstreamConfig {
+    *CONFIG
+}
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(CONFIG.connect_timeout)
+        .read_timeout(CONFIG.read_timeout)
+        .build()
+        .expect("could not build the upstream HTTP client")
+});
+
+/// Shared client for gateway and storage proof requests.
+pub fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.clone()
+}
+
+/// An upstream whose circuit is open, calls to it fail without being made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamUnavailable {
+    pub upstream: String,
+}
+
+impl fmt::Display for UpstreamUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream unavailable: {}", self.upstream)
+    }
+}
+
+impl std::error::Error for UpstreamUnavailable {}
+
+impl UpstreamUnavailable {
+    /// The open circuit behind `err`, if any.
+    pub fn find(err: &Error) -> Option<&UpstreamUnavailable> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+/// An upstream with all its slots taken and a full queue, the call was
+/// rejected without being made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamBusy {
+    pub upstream: String,
+}
+
+impl fmt::Display for UpstreamBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream busy: {}", self.upstream)
+    }
+}
+
+impl std::error::Error for UpstreamBusy {}
+
+impl UpstreamBusy {
+    /// The full queue behind `err`, if any.
+    pub fn find(err: &Error) -> Option<&UpstreamBusy> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+/// Whether `err` is an upstream refusing work, an open circuit or a full
+/// queue, which is reported to the client rather than degraded around.
+pub fn is_upstream_rejection(err: &Error) -> bool {
+    UpstreamUnavailable::find(err).is_some() || UpstreamBusy::find(err).is_some()
+}
+
+/// Whether a failure says something about the upstream's health. Client
+/// errors such as a 404 are neither retried nor held against the upstream.
+pub trait UpstreamError: std::error::Error + Send + Sync + 'static {
+    fn is_transient(&self) -> bool {
+        true
+    }
+}
+
+/// A body that doesn't decode was answered all the same.
+impl UpstreamError for reqwest::Error {
+    fn is_transient(&self) -> bool {
+        !self.is_decode()
+            && self.status().is_none_or(|status| {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            })
+    }
+}
+
+/// A JSON-RPC error response means the node answered, e.g. an unknown method
+/// or invalid params, unless it is the node's rate limit.
+impl UpstreamError for ethers_providers::ProviderError {
+    fn is_transient(&self) -> bool {
+        ethers_providers::RpcError::as_error_response(self)
+            .is_none_or(|response| RPC_RATE_LIMIT_CODES.contains(&response.code))
+    }
+}
+
+/// JSON-RPC error codes nodes use for rate limiting.
+const RPC_RATE_LIMIT_CODES: [i64; 2] = [-32005, 429];
+
+/// Failure of a JSON-RPC call made with the plain HTTP client.
+#[derive(Debug)]
+pub enum JsonRpcCallError {
+    Http(reqwest::Error),
+    /// The node answered with a JSON-RPC error object.
+    Rpc {
+        code: i64,
+        message: String,
+    },
+}
+
+impl fmt::Display for JsonRpcCallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonRpcCallError::Http(e) => write!(f, "{}", e),
+            JsonRpcCallError::Rpc { code, message } => {
+                write!(f, "JSON-RPC error {}: {}", code, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonRpcCallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonRpcCallError::Http(e) => Some(e),
+            JsonRpcCallError::Rpc { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for JsonRpcCallError {
+    fn from(e: reqwest::Error) -> Self {
+        JsonRpcCallError::Http(e)
+    }
+}
+
+/// Classified like the `ProviderError` of the ethers client.
+impl UpstreamError for JsonRpcCallError {
+    fn is_transient(&self) -> bool {
+        match self {
+            JsonRpcCallError::Http(e) => e.is_transient(),
+            JsonRpcCallError::Rpc { code, .. } => RPC_RATE_LIMIT_CODES.contains(code),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonRpcEnvelope<T> {
+    result: Option<T>,
+    error: Option<JsonRpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcErrorObject {
+    code: i64,
+    #[serde(default)]
+    message: String,
+}
+
+/// The `result` of a JSON-RPC response, `None` when it is null.
+pub async fn json_rpc_result<T: DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<Option<T>, JsonRpcCallError> {
+    let envelope: JsonRpcEnvelope<T> = response.error_for_status()?.json().await?;
+    match envelope.error {
+        Some(JsonRpcErrorObject { code, message }) => Err(JsonRpcCallError::Rpc { code, message }),
+        None => Ok(envelope.result),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One probe call is let through, and another one past `next_probe` in
+    /// case the first was cancelled.
+    HalfOpen {
+        next_probe: Instant,
+    },
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+}
+
+impl Breaker {
+    fn new() -> Self {
+        Breaker {
+            state: BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    fn allow(&mut self, now: Instant, config: &UpstreamConfig) -> bool {
+        match self.state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until: at } | BreakerState::HalfOpen { next_probe: at }
+                if now >= at =>
+            {
+                self.state = BreakerState::HalfOpen {
+                    next_probe: now + config.breaker_open_for,
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn on_success(&mut self) {
+        self.state = BreakerState::Closed { failures: 0 };
+    }
+
+    fn on_failure(&mut self, now: Instant, config: &UpstreamConfig) {
+        self.state = match self.state {
+            BreakerState::Closed { failures } if failures + 1 < config.breaker_threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => BreakerState::Open {
+                until: now + config.breaker_open_for,
+            },
+        };
+    }
+
+    fn is_open(&self) -> bool {
+        !matches!(self.state, BreakerState::Closed { .. })
+    }
+}
+
+static BREAKERS: LazyLock<Mutex<HashMap<String, Breaker>>> = LazyLock::new(Default::default);
+
+fn with_breaker<T>(upstream: &str, f: impl FnOnce(&mut Breaker) -> T) -> T {
+    let mut breakers = BREAKERS.lock().unwrap();
+    let breaker = breakers
+        .entry(upstream.to_string())
+        .or_insert_with(Breaker::new);
+    let result = f(breaker);
+    set_circuit_open(upstream, breaker.is_open());
+    result
+}
+
+/// Bounds the calls in flight to one upstream, with a bounded queue of
+/// calls waiting for a slot.
+struct Limiter {
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queue: usize,
+}
+
+impl Limiter {
+    fn new(config: &UpstreamConfig) -> Self {
+        Limiter {
+            slots: Arc::new(Semaphore::new(config.max_concurrency)),
+            queued: AtomicUsize::new(0),
+            max_queue: config.max_queue,
+        }
+    }
+
+    async fn acquire(&self, upstream: &str) -> Result<OwnedSemaphorePermit, UpstreamBusy> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let busy = || UpstreamBusy {
+            upstream: upstream.to_string(),
+        };
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queue {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            observe_upstream_rejected(upstream);
+            return Err(busy());
+        }
+        set_upstream_queued(upstream, self.queued.load(Ordering::Acquire));
+        // leaves the queue even when the waiting call is cancelled
+        let _queued = QueueSlot(self, upstream);
+        self.slots.clone().acquire_owned().await.map_err(|_| busy())
+    }
+}
+
+struct QueueSlot<'a>(&'a Limiter, &'a str);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let queued = self.0.queued.fetch_sub(1, Ordering::AcqRel) - 1;
+        set_upstream_queued(self.1, queued);
+    }
+}
+
+static LIMITERS: LazyLock<Mutex<HashMap<String, Arc<Limiter>>>> = LazyLock::new(Default::default);
+
+fn limiter(upstream: &str) -> Arc<Limiter> {
+    LIMITERS
+        .lock()
+        .unwrap()
+        .entry(upstream.to_string())
+        .or_insert_with(|| Arc::new(Limiter::new(&CONFIG)))
+        .clone()
+}
+
+/// Full jitter: a random wait up to the exponential backoff of `attempt`.
+fn backoff(attempt: u32, config: &UpstreamConfig) -> Duration {
+    let ceiling = config
+        .retry_base
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(config.retry_max);
+    Duration::from_millis(fastrand::u64(..=ceiling.as_millis() as u64))
+}
+
+/// A slot of an upstream's concurrency limit, given back when dropped.
+#[derive(Debug)]
+pub struct UpstreamSlot {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Makes an idempotent upstream call through the upstream's circuit breaker
+/// and concurrency limit, retrying transient failures with jittered
+/// exponential backoff. Fails with `UpstreamUnavailable` right away while the
+/// circuit is open, and with `UpstreamBusy` when the upstream's queue is full.
+/// A slot is held for each attempt, not while backing off.
+pub async fn call_upstream<T, E, F, Fut>(
+    upstream: &str,
+    operation: &str,
+    call: F,
+) -> Result<T, Error>
+where
+    E: UpstreamError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let (value, _slot) = call_upstream_holding_slot(upstream, operation, call).await?;
+    Ok(value)
+}
+
+/// Like `call_upstream`, but the slot of the successful attempt is handed
+/// back with its result, for calls whose work goes on once they return, such
+/// as a response whose body is still to be read.
+pub async fn call_upstream_holding_slot<T, E, F, Fut>(
+    upstream: &str,
+    operation: &str,
+    mut call: F,
+) -> Result<(T, UpstreamSlot), Error>
+where
+    E: UpstreamError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let config = upstream_config();
+    let limiter = limiter(upstream);
+    let mut attempt = 0;
+    loop {
+        if !with_breaker(upstream, |breaker| breaker.allow(Instant::now(), &config)) {
+            return Err(Error::new(UpstreamUnavailable {
+                upstream: upstream.to_string(),
+            }));
+        }
+        let permit = limiter.acquire(upstream).await?;
+        let res = observe_upstream(upstream, operation, call()).await;
+        match res {
+            Ok(value) => {
+                with_breaker(upstream, Breaker::on_success);
+                return Ok((value, UpstreamSlot { _permit: permit }));
+            }
+            Err(e) if e.is_transient() => {
+                drop(permit);
+                with_breaker(upstream, |breaker| {
+                    breaker.on_failure(Instant::now(), &config)
+                });
+                if attempt >= config.max_retries {
+                    return Err(Error::new(e));
+                }
+                tracing::debug!(upstream, operation, attempt, error = %e, "retrying");
+                tokio::time::sleep(backoff(attempt, &config)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                // the upstream answered, so it is healthy
+                with_breaker(upstream, Breaker::on_success);
+                return Err(Error::new(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: UpstreamConfig = UpstreamConfig {
+        connect_timeout: Duration::from_secs(1),
+        read_timeout: Duration::from_secs(1),
+        max_retries: 2,
+        retry_base: Duration::from_millis(100),
+        retry_max: Duration::from_millis(1000),
+        breaker_threshold: 3,
+        breaker_open_for: Duration::from_secs(30),
+        max_concurrency: 1,
+        max_queue: 1,
+        busy_retry_after: Duration::from_secs(1),
+    };
+
+    #[test]
+    fn test_breaker_opens_and_recovers() {
+        let start = Instant::now();
+        let mut breaker = Breaker::new();
+        for _ in 0..2 {
+            breaker.on_failure(start, &CONFIG);
+            assert!(breaker.allow(start, &CONFIG));
+        }
+        breaker.on_failure(start, &CONFIG);
+        assert!(!breaker.allow(start, &CONFIG));
+
+        // one probe once the open period is over, which closes it on success
+        let later = start + CONFIG.breaker_open_for;
+        assert!(breaker.allow(later, &CONFIG));
+        assert!(!breaker.allow(later, &CONFIG));
+        breaker.on_success();
+        assert!(breaker.allow(later, &CONFIG));
+
+        // a failed probe opens it again
+        breaker.state = BreakerState::HalfOpen { next_probe: later };
+        breaker.on_failure(later, &CONFIG);
+        assert!(!breaker.allow(later, &CONFIG));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        for attempt in 0..10 {
+            assert!(backoff(attempt, &CONFIG) <= CONFIG.retry_max);
+        }
+        assert!(backoff(0, &CONFIG) <= CONFIG.retry_base);
+    }
+
+    #[tokio::test]
+    async fn test_limiter_rejects_past_queue() {
+        let limiter = Arc::new(Limiter::new(&CONFIG));
+        let held = limiter.acquire("test-busy").await.unwrap();
+
+        // one caller fits in the queue, the next one is turned away
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("test-busy").await.map(drop) }
+        });
+        while limiter.queued.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let busy = limiter.acquire("test-busy").await.unwrap_err();
+        assert_eq!(busy.upstream, "test-busy");
+
+        drop(held);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(limiter.queued.load(Ordering::Acquire), 0);
+    }
+
+    #[derive(Debug)]
+    struct Flaky;
+
+    impl fmt::Display for Flaky {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "flaky")
+        }
+    }
+
+    impl std::error::Error for Flaky {}
+    impl UpstreamError for Flaky {}
+
+    #[test]
+    fn test_rpc_error_responses_are_not_transient() {
+        use ethers_providers::{HttpClientError, JsonRpcError, ProviderError};
+
+        let response = |code| {
+            ProviderError::JsonRpcClientError(Box::new(HttpClientError::JsonRpcError(
+                JsonRpcError {
+                    code,
+                    message: "error".to_string(),
+                    data: None,
+                },
+            )))
+        };
+        assert!(!response(-32601).is_transient());
+        assert!(!response(-32602).is_transient());
+        assert!(response(-32005).is_transient());
+        assert!(ProviderError::CustomError("connection reset".to_string()).is_transient());
+    }
+
+    fn json_response(body: &'static str) -> reqwest::Response {
+        reqwest::Response::from(axum::http::Response::new(body))
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc_error_bodies() {
+        let rpc_error = |code: i64| {
+            json_response(if code == -32005 {
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32005,"message":"limit exceeded"}}"#
+            } else {
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"invalid params"}}"#
+            })
+        };
+        let err = json_rpc_result::<String>(rpc_error(-32602))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, JsonRpcCallError::Rpc { code: -32602, .. }));
+        assert!(!err.is_transient());
+        let err = json_rpc_result::<String>(rpc_error(-32005))
+            .await
+            .unwrap_err();
+        assert!(err.is_transient());
+        let err = json_rpc_result::<String>(json_response("<html>"))
+            .await
+            .unwrap_err();
+        assert!(!err.is_transient());
+        let null = json_response(r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        assert_eq!(json_rpc_result::<String>(null).await.unwrap(), None);
+
+        // answered errors are neither retried nor held against the upstream
+        let threshold = upstream_config().breaker_threshold;
+        for _ in 0..=threshold {
+            let mut calls = 0;
+            let err = call_upstream("test-rpc-error", "op", || {
+                calls += 1;
+                json_rpc_result::<String>(rpc_error(-32602))
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(calls, 1);
+            assert!(UpstreamUnavailable::find(&err).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slot_is_held_until_dropped() {
+        let slots = limiter("test-held").slots.clone();
+        let available = slots.available_permits();
+        let (value, slot) =
+            call_upstream_holding_slot("test-held", "op", || async { Ok::<_, Flaky>(1) })
+                .await
+                .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(slots.available_permits(), available - 1);
+        drop(slot);
+        assert_eq!(slots.available_permits(), available);
+
+        call_upstream("test-held", "op", || async { Ok::<_, Flaky>(()) })
+            .await
+            .unwrap();
+        assert_eq!(slots.available_permits(), available);
+    }
+
+    #[tokio::test]
+    async fn test_call_upstream_retries_then_fails_fast() {
+        let mut calls = 0;
+        let res = call_upstream("test-flaky", "op", || {
+            calls += 1;
+            let ok = calls == 2;
+            async move {
+                if ok {
+                    Ok(calls)
+                } else {
+                    Err(Flaky)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 2);
+
+        let threshold = upstream_config().breaker_threshold;
+        for _ in 0..threshold {
+            let _ = call_upstream("test-down", "op", || async { Err::<(), _>(Flaky) }).await;
+        }
+        let err = call_upstream("test-down", "op", || async { Ok::<_, Flaky>(()) })
+            .await
+            .unwrap_err();
+        assert!(UpstreamUnavailable::find(&err).is_some());
+    }
+}
@@ -1,38 +1,153 @@
 use crate::utils::constants::WVM_RPC_URL;
+use crate::utils::limits::LimitError;
+use crate::utils::metrics::{observe_decode, observe_payload, WVM_RPC};
 use crate::utils::schemas::{EncodingUtils, GetBlockFromTx, WeaveVMArchiverBlock};
+use crate::utils::single_flight::{FlightError, SingleFlight};
+use crate::utils::telemetry::outgoing_headers;
+use crate::utils::upstream::{call_upstream, upstream_config};
+use anyhow::Error;
 use ethers::prelude::*;
 use ethers::types::H256;
-use ethers::utils::hex;
 use ethers::utils::rlp;
 use ethers_providers::{Http, JsonRpcClient, Provider};
+use reth_primitives::B256;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Instant;
 
 pub async fn retrieve_wvm_block_ref_from_txid(txid: &str) -> GetBlockFromTx {
-    let provider: Provider<Http> =
-        Provider::<Http>::try_from(WVM_RPC_URL).expect("could not instantiate HTTP Provider");
-    let txid = H256::from_str(&txid).unwrap();
-    let tx = provider.get_transaction(txid).await.unwrap();
-
-    let tx_json = serde_json::json!(&tx);
-    let block_hash: &str = tx_json["blockHash"].as_str().unwrap_or("0x");
-    let block_number_hex: &str = tx_json["blockNumber"].as_str().unwrap_or("0x");
-    let block_number_dec = U256::from_str(block_number_hex).unwrap_or(U256::zero());
-    let calldata: &str = tx_json["input"].as_str().unwrap_or("0x");
-
-    GetBlockFromTx::new(block_number_dec, block_hash.into(), calldata.into())
+    let txid = B256::from_str(txid.trim()).unwrap();
+    try_retrieve_wvm_block_ref_from_txid(txid).await.unwrap()
 }
 
-pub async fn decode_calldata_to_wvm_archiver(calldata: &String) -> WeaveVMArchiverBlock {
-    let byte_array = hex::decode(calldata.trim_start_matches("0x")).expect("decoding failed");
-    let unbrotli = EncodingUtils::brotli_decompress(byte_array);
-    let unborsh: WeaveVMArchiverBlock =
-        EncodingUtils::wvm_archiver_borsh_deserialize(unbrotli.unwrap());
-    unborsh
+static TX_LOOKUPS: LazyLock<SingleFlight<B256, Result<GetBlockFromTx, FlightError>>> =
+    LazyLock::new(Default::default);
+
+pub async fn try_retrieve_wvm_block_ref_from_txid(txid: B256) -> Result<GetBlockFromTx, Error> {
+    TX_LOOKUPS
+        .run(txid, || async move {
+            fetch_wvm_block_ref_from_txid(txid)
+                .await
+                .map_err(FlightError::from)
+        })
+        .await
+        .map_err(Error::from)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// WeaveVM RPC provider whose requests carry the request id and trace
+/// context of the caller, bounded by the upstream timeouts.
+fn wvm_provider() -> Result<Provider<Http>, Error> {
+    let mut headers = ethers_reqwest::header::HeaderMap::new();
+    for (name, value) in outgoing_headers() {
+        headers.insert(
+            ethers_reqwest::header::HeaderName::from_str(&name)?,
+            ethers_reqwest::header::HeaderValue::from_str(&value)?,
+        );
+    }
+    let config = upstream_config();
+    let client = ethers_reqwest::Client::builder()
+        .default_headers(headers)
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.read_timeout)
+        .build()?;
+    let url = ethers_reqwest::Url::parse(WVM_RPC_URL)?;
+    Ok(Provider::new(Http::new_with_client(url, client)))
+}
+
+#[tracing::instrument(skip_all, fields(txid = %txid))]
+async fn fetch_wvm_block_ref_from_txid(txid: B256) -> Result<GetBlockFromTx, Error> {
+    let provider = wvm_provider()?;
+    let tx = call_upstream(WVM_RPC, "eth_getTransactionByHash", || {
+        provider.get_transaction(H256(txid.0))
+    })
+    .await?;
+    Ok(block_ref_of(tx.as_ref()))
+}
+
+/// Block reference of an RPC transaction, with the ethers types mapped to the
+/// internal byte types. A missing or pending tx has no block hash.
+fn block_ref_of(tx: Option<&Transaction>) -> GetBlockFromTx {
+    match tx {
+        Some(tx) => GetBlockFromTx::new(
+            tx.block_number
+                .map(|number| U256::from(number.as_u64()))
+                .unwrap_or_default(),
+            tx.block_hash.map(|hash| B256::from(hash.0)),
+            reth_primitives::Bytes(tx.input.0.clone()),
+        ),
+        None => GetBlockFromTx::new(U256::zero(), None, reth_primitives::Bytes::new()),
+    }
+}
+
+#[tracing::instrument]
+pub async fn retrieve_wvm_head_block_number() -> Result<u64, Error> {
+    let provider = wvm_provider()?;
+    let head = call_upstream(WVM_RPC, "eth_blockNumber", || provider.get_block_number()).await?;
+    Ok(head.as_u64())
+}
+
+#[tracing::instrument]
+pub async fn retrieve_wvm_transaction(txid: &str) -> Result<Option<Transaction>, Error> {
+    let provider = wvm_provider()?;
+    let txid = H256::from_str(txid)?;
+    let tx = call_upstream(WVM_RPC, "eth_getTransactionByHash", || {
+        provider.get_transaction(txid)
+    })
+    .await?;
+    Ok(tx)
+}
+
+#[tracing::instrument]
+pub async fn retrieve_wvm_transaction_by_txtag(
+    tag: [String; 2],
+) -> Result<Option<Transaction>, Error> {
+    let provider = wvm_provider()?;
+    let tx = get_wvm_transaction_by_tag(&provider, tag).await?;
+    // the RLP-decoded tx carries no block context, so refetch it by hash when mined
+    match tx {
+        Some(tx) => {
+            let mined = call_upstream(WVM_RPC, "eth_getTransactionByHash", || {
+                provider.get_transaction(tx.hash)
+            })
+            .await?;
+            Ok(Some(mined.unwrap_or(tx)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[tracing::instrument]
+pub async fn retrieve_wvm_block_header(number: u64) -> Result<Option<Block<H256>>, Error> {
+    let provider = wvm_provider()?;
+    let block = call_upstream(WVM_RPC, "eth_getBlockByNumber", || {
+        provider.get_block(number)
+    })
+    .await?;
+    Ok(block)
+}
+
+/// Decodes raw wvm-archiver calldata, reporting malformed calldata as an
+/// error. Calldata inflating past the `DecodeLimits` fails with a
+/// `LimitError`.
+#[tracing::instrument(skip_all, fields(calldata_len = calldata.len()))]
+pub async fn try_decode_calldata_to_wvm_archiver(
+    calldata: &[u8],
+) -> Result<WeaveVMArchiverBlock, Error> {
+    let started = Instant::now();
+    observe_payload("war", calldata.len());
+    let unbrotli = EncodingUtils::brotli_decompress(calldata.to_vec()).map_err(|e| {
+        match e.into_inner().downcast::<LimitError>() {
+            Ok(limit) => Error::new(*limit),
+            Err(e) => Error::msg(e.to_string()),
+        }
+    })?;
+    let decoded = EncodingUtils::try_wvm_archiver_borsh_deserialize(unbrotli);
+    observe_decode("war", started);
+    decoded
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct GetWvmTransactionByTagRequest {
     tag: [String; 2],
 }
@@ -40,7 +155,8 @@ struct GetWvmTransactionByTagRequest {
 use axum::async_trait;
 #[async_trait]
 pub trait WvmJsonRpc {
-    async fn get_wvm_transaction_by_tag(&self, tag: [String; 2]) -> Option<Bytes>;
+    /// Raw RLP of the tx carrying `tag`, `None` when no tx does.
+    async fn get_wvm_transaction_by_tag(&self, tag: [String; 2]) -> Result<Option<Bytes>, Error>;
 }
 
 #[async_trait]
@@ -48,55 +164,55 @@ impl<P> WvmJsonRpc for Provider<P>
 where
     P: JsonRpcClient + 'static,
 {
-    async fn get_wvm_transaction_by_tag(&self, tag: [String; 2]) -> Option<Bytes> {
+    async fn get_wvm_transaction_by_tag(&self, tag: [String; 2]) -> Result<Option<Bytes>, Error> {
         let req = GetWvmTransactionByTagRequest { tag };
-        self.request("eth_getWvmTransactionByTag", (req,))
-            .await
-            .expect("RPC failed")
+        call_upstream(WVM_RPC, "eth_getWvmTransactionByTag", || {
+            self.request("eth_getWvmTransactionByTag", (req.clone(),))
+        })
+        .await
     }
 }
 
-pub async fn retrieve_wvm_block_ref_from_txtag(tag: [String; 2]) -> (GetBlockFromTx, String) {
-    let provider =
-        Provider::<Http>::try_from(WVM_RPC_URL).expect("could not instantiate HTTP Provider");
-
-    let (block_data, tx_hash) = retrieve_txtag(&provider, tag).await;
-    (block_data, tx_hash)
+/// Block reference and hash of the tx carrying `tag`, `None` when no tx
+/// does. Fails when the RPC is unavailable.
+#[tracing::instrument]
+pub async fn retrieve_wvm_block_ref_from_txtag(
+    tag: [String; 2],
+) -> Result<Option<(GetBlockFromTx, B256)>, Error> {
+    let provider = wvm_provider()?;
+    let Some((block_ref, txid)) = retrieve_txtag(&provider, tag).await? else {
+        return Ok(None);
+    };
+    // the RLP-decoded tx carries no block context, so look it up by hash
+    let mined = try_retrieve_wvm_block_ref_from_txid(txid).await?;
+    match mined.hash {
+        Some(_) => Ok(Some((mined, txid))),
+        None => Ok(Some((block_ref, txid))),
+    }
 }
 
-async fn retrieve_txtag<P>(provider: &P, tag: [String; 2]) -> (GetBlockFromTx, String)
+async fn retrieve_txtag<P>(
+    provider: &P,
+    tag: [String; 2],
+) -> Result<Option<(GetBlockFromTx, B256)>, Error>
 where
     P: WvmJsonRpc + 'static,
 {
-    let tx: Option<Transaction> = get_wvm_transaction_by_tag(provider, tag).await.unwrap();
-
-    let tx_json = serde_json::json!(&tx);
-    let block_hash: &str = tx_json["blockHash"].as_str().unwrap_or("0x");
-    let block_number_hex: &str = tx_json["blockNumber"].as_str().unwrap_or("0x");
-    let block_number_dec = U256::from_str(block_number_hex).unwrap_or(U256::zero());
-    let calldata: &str = tx_json["input"].as_str().unwrap_or("0x");
-    // Convert the H256 to the full hex string:
-    let raw_tx = tx.unwrap();
-    let full_tx_hash_str = format!("0x{}", hex::encode(raw_tx.hash.as_bytes()));
-
-    (
-        GetBlockFromTx::new(block_number_dec, block_hash.into(), calldata.into()),
-        full_tx_hash_str,
-    )
+    let tx = get_wvm_transaction_by_tag(provider, tag).await?;
+    Ok(tx.map(|tx| (block_ref_of(Some(&tx)), B256::from(tx.hash.0))))
 }
 
 async fn get_wvm_transaction_by_tag<P>(
     provider_extended: &P,
     tag: [String; 2],
-) -> Result<Option<Transaction>, String>
+) -> Result<Option<Transaction>, Error>
 where
     P: WvmJsonRpc + 'static,
 {
-    let raw_tx_opt = provider_extended.get_wvm_transaction_by_tag(tag).await;
-    match raw_tx_opt {
+    match provider_extended.get_wvm_transaction_by_tag(tag).await? {
         Some(raw_tx) => {
             let tx: Transaction = rlp::decode(raw_tx.as_ref())
-                .map_err(|e| format!("Failed to RLP-decode raw transaction: {e}"))?;
+                .map_err(|e| Error::msg(format!("Failed to RLP-decode raw transaction: {e}")))?;
             Ok(Some(tx))
         }
         None => Ok(None),
@@ -134,21 +250,32 @@ mod tests {
     // This is why blockHash and blockNumber typically default to "0x"/0
     // if you’re just decoding the RLP or if the tx is pending.
     // ------------------------------------------------------------
+    use crate::utils::upstream::{call_upstream, UpstreamUnavailable};
     use crate::utils::wvm_client::Bytes;
     use crate::utils::wvm_client::WvmJsonRpc;
+    use anyhow::Error;
     use ethers::types::{Address, Signature, Transaction, H256, U256};
     use ethers::utils::{hex, keccak256, rlp};
+    use ethers_providers::ProviderError;
     use rlp::RlpStream;
 
+    /// Answers tag lookups through the circuit breaker of `upstream`.
     #[derive(Default)]
     struct MockWvmProvider {
+        pub upstream: &'static str,
         pub response: Option<Bytes>,
     }
 
     #[axum::async_trait]
     impl WvmJsonRpc for MockWvmProvider {
-        async fn get_wvm_transaction_by_tag(&self, _tag: [String; 2]) -> Option<Bytes> {
-            self.response.clone()
+        async fn get_wvm_transaction_by_tag(
+            &self,
+            _tag: [String; 2],
+        ) -> Result<Option<Bytes>, Error> {
+            call_upstream(self.upstream, "eth_getWvmTransactionByTag", || async {
+                Ok::<_, ProviderError>(self.response.clone())
+            })
+            .await
         }
     }
     #[tokio::test]
@@ -158,24 +285,55 @@ mod tests {
             hex::decode(raw_tx_hex.trim_start_matches("0x")).expect("Could not decode hex string");
 
         let mock_provider = MockWvmProvider {
+            upstream: "test-tag",
             response: Some(Bytes::from(raw_tx_bytes)),
         };
 
         let tag = ["testtag1".to_string(), "testtag2".to_string()];
 
-        let (block_ref, txid) = super::retrieve_txtag(&mock_provider, tag).await;
+        let (block_ref, txid) = super::retrieve_txtag(&mock_provider, tag)
+            .await
+            .unwrap()
+            .expect("Expected the tagged tx");
 
         assert_eq!(
-            txid,
+            format!("{:#x}", txid),
             "0xb97c966f2d5f675c6fdc632c1bcb9056bdeb3c24aaeabe54ac0b728200a47f8a",
         );
-        assert_eq!(block_ref.hash, "0x", "Expected default blockHash");
+        assert_eq!(block_ref.hash, None, "Expected default blockHash");
         assert_eq!(
             block_ref.number,
             U256::from(0),
             "Expected default blockNumber"
         );
-        assert_eq!(block_ref.calldata, "0x", "Expected default input");
+        assert!(block_ref.calldata.is_empty(), "Expected default input");
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_txtag_failures() {
+        let tag = || ["testtag1".to_string(), "testtag2".to_string()];
+        let unknown = MockWvmProvider {
+            upstream: "test-tag-unknown",
+            response: None,
+        };
+        assert!(super::retrieve_txtag(&unknown, tag())
+            .await
+            .unwrap()
+            .is_none());
+
+        // an open breaker is reported, not panicked on
+        let down = MockWvmProvider {
+            upstream: "test-tag-down",
+            response: None,
+        };
+        for _ in 0..crate::utils::upstream::upstream_config().breaker_threshold {
+            let _ = call_upstream(down.upstream, "op", || async {
+                Err::<(), _>(ProviderError::CustomError("down".to_string()))
+            })
+            .await;
+        }
+        let err = super::retrieve_txtag(&down, tag()).await.unwrap_err();
+        assert!(UpstreamUnavailable::find(&err).is_some());
     }
 
     /// Helper function to compute the sighash for an EIP-1559 (type 2) transaction.